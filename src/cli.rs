@@ -0,0 +1,193 @@
+use std::{path::PathBuf, str::FromStr};
+
+use crate::modular::{LIBRARIES, MODULES};
+
+pub const USAGE: &str = "\
+Usage: mephisto-mm2-emu [options]
+
+  --mode MODE          uci (default), xboard, interactive, bench, epd or selfplay
+  --module NAME        MM2 (default), MM4 or MM5
+  --library NAME       Default, HG240, HG170, HG550 or None
+  --rom-path PATH      directory or zip set to look for ROMs in, can be repeated
+  --rom NAME=PATH      take ROM NAME from PATH, can be repeated
+  --clock MODE         Fast (default), RealTime or Scaled
+  --speed N            how much faster than the real machine Scaled runs
+  --difficulty N       level 1 to 10, default 1
+  --log FILE           write everything sent and received to FILE
+  --movetime MS        thinking time per move in bench, epd and selfplay, default 5000
+  --epd FILE           positions for the epd mode
+  --games N            number of games in selfplay, default 1
+  --opponent NAME      module to play against in selfplay, default the same
+  --help               show this
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Uci,
+    Xboard,
+    Interactive,
+    Bench,
+    Epd,
+    Selfplay,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s.to_lowercase().as_str() {
+            "uci" => Ok(Mode::Uci),
+            "xboard" | "cecp" => Ok(Mode::Xboard),
+            "interactive" => Ok(Mode::Interactive),
+            "bench" => Ok(Mode::Bench),
+            "epd" => Ok(Mode::Epd),
+            "selfplay" => Ok(Mode::Selfplay),
+            _ => Err(format!("Unknown mode {s}!")),
+        }
+    }
+}
+
+pub struct Options {
+    pub mode: Mode,
+    pub module: String,
+    pub library: String,
+    pub rom_paths: Vec<PathBuf>,
+    pub roms: Vec<(String, PathBuf)>,
+    pub clock_mode: String,
+    pub speed: u32,
+    pub difficulty: u8,
+    pub log: Option<String>,
+    pub movetime: u64,
+    pub epd: Option<PathBuf>,
+    pub games: u32,
+    pub opponent: Option<String>,
+}
+
+impl Default for Options {
+    // what the emulator did before it took any arguments
+    fn default() -> Options {
+        Options {
+            mode: Mode::Uci,
+            module: MODULES[0].to_string(),
+            library: LIBRARIES[0].to_string(),
+            rom_paths: Vec::new(),
+            roms: Vec::new(),
+            clock_mode: "Fast".to_string(),
+            speed: 1,
+            difficulty: 1,
+            log: None,
+            movetime: 5000,
+            epd: None,
+            games: 1,
+            opponent: None,
+        }
+    }
+}
+
+fn number<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{flag} needs a number, not {value}!"))
+}
+
+fn one_of(flag: &str, value: &str, known: &[&str]) -> Result<String, String> {
+    match known.iter().find(|k| k.eq_ignore_ascii_case(value)) {
+        Some(k) => Ok(k.to_string()),
+        None => Err(format!(
+            "{flag} must be one of {}, not {value}!",
+            known.join(", ")
+        )),
+    }
+}
+
+// Ok(None) if only the usage was asked for
+pub fn parse(args: &[String]) -> Result<Option<Options>, String> {
+    let mut opts = Options::default();
+    let mut args = args.iter().skip(1);
+    while let Some(flag) = args.next() {
+        if flag == "--help" || flag == "-h" {
+            return Ok(None);
+        }
+        let value = args
+            .next()
+            .ok_or(format!("{flag} needs a value!"))?
+            .as_str();
+        match flag.as_str() {
+            "--mode" => opts.mode = value.parse()?,
+            "--module" => opts.module = one_of(flag, value, &MODULES)?,
+            "--library" => opts.library = one_of(flag, value, &LIBRARIES)?,
+            "--rom-path" => opts.rom_paths.push(PathBuf::from(value)),
+            "--rom" => match value.split_once('=') {
+                Some((name, path)) => opts.roms.push((name.to_string(), PathBuf::from(path))),
+                None => return Err(format!("--rom takes NAME=PATH, not {value}!")),
+            },
+            "--clock" => opts.clock_mode = one_of(flag, value, &["Fast", "RealTime", "Scaled"])?,
+            "--speed" => {
+                opts.speed = number(flag, value)?;
+                if !(1..=1000).contains(&opts.speed) {
+                    return Err("--speed can only be from 1 to 1000!".to_string());
+                }
+            }
+            "--difficulty" => {
+                opts.difficulty = number(flag, value)?;
+                if !(1..=10).contains(&opts.difficulty) {
+                    return Err("--difficulty can only be from 1 to 10!".to_string());
+                }
+            }
+            "--log" => opts.log = Some(value.to_string()),
+            "--movetime" => opts.movetime = number(flag, value)?,
+            "--epd" => opts.epd = Some(PathBuf::from(value)),
+            "--games" => opts.games = number(flag, value)?,
+            "--opponent" => opts.opponent = Some(one_of(flag, value, &MODULES)?),
+            _ => return Err(format!("Unknown option {flag}!")),
+        }
+    }
+    if opts.mode == Mode::Epd && opts.epd.is_none() {
+        return Err("The epd mode needs --epd FILE!".to_string());
+    }
+    Ok(Some(opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn error(line: &str) -> String {
+        parse(&args(line)).err().unwrap()
+    }
+
+    #[test]
+    fn parses_flags() {
+        let opts = parse(&args(
+            "emu --module mm5 --rom MM2.rom=/roms/a=b.bin --speed 10",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(opts.module, "MM5");
+        assert_eq!(
+            opts.roms,
+            [("MM2.rom".to_string(), PathBuf::from("/roms/a=b.bin"))]
+        );
+        assert_eq!(opts.speed, 10);
+        assert!(parse(&args("emu --help")).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_flags() {
+        assert_eq!(error("emu --module"), "--module needs a value!");
+        assert_eq!(error("emu --colour white"), "Unknown option --colour!");
+        assert_eq!(
+            error("emu --rom MM2.rom"),
+            "--rom takes NAME=PATH, not MM2.rom!"
+        );
+        assert_eq!(
+            error("emu --speed fast"),
+            "--speed needs a number, not fast!"
+        );
+        assert_eq!(error("emu --mode epd"), "The epd mode needs --epd FILE!");
+        assert!(parse(&args("emu --mode epd --epd wac.epd")).is_ok());
+    }
+}
@@ -1,108 +1,27 @@
 use core::panic;
 use std::{
+    collections::HashMap,
     io::{Error, ErrorKind},
     str::FromStr,
     sync::mpsc::{Receiver, TryRecvError},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use crate::utils::read_file_into_slice;
-use chess::{Board, ChessMove, Color, File, Piece, Rank, Square};
+use crate::{
+    modular::{Bus, Hardware, Module, LCD_MAP},
+    ram::{
+        before_double_push, legal_move, placement, read_learned, rights_implied, to_board,
+        BoardLayout, MoveSlots, ValueSlot,
+    },
+    rom::RomLoader,
+    savestate::MachineState,
+    timing::{move_budget, ClockPolicy, Throttle, Timing},
+};
+use chess::{Board, ChessMove, Color, File, MoveGen, Piece, Rank, Square};
 use vampirc_uci::{UciFen, UciInfoAttribute, UciMessage, UciTimeControl};
-use w65c02s::{System, W65C02S};
-const fn calc_lcd_map() -> [char; 0x100] {
-    let mut res = ['☐'; 0x100];
-    let vals = [
-        (' ', 0b11111111),
-        ('-', 0b11110111),
-        ('a', 0b10100000),
-        ('b', 0b10000011),
-        ('c', 0b10100111),
-        ('d', 0b10100001),
-        ('e', 0b10000100),
-        ('f', 0b10001110),
-        ('g', 0b10010000),
-        ('h', 0b10001011),
-        ('i', 0b11101111),
-        ('j', 0b11110011),
-        ('k', 0b10001010),
-        ('l', 0b11001111),
-        ('m', 0b11101011),
-        ('n', 0b10101011),
-        ('o', 0b10100011),
-        ('p', 0b10001100),
-        ('q', 0b10011000),
-        ('r', 0b10101111),
-        ('s', 0b10010010),
-        ('t', 0b10000111),
-        ('u', 0b11100011),
-        ('v', 0b11100011),
-        ('w', 0b11101011),
-        ('x', 0b10001001),
-        ('y', 0b10010001),
-        ('z', 0b10100100),
-        ('A', 0b10001000),
-        ('B', 0b10000011),
-        ('C', 0b11000110),
-        ('D', 0b10100001),
-        ('E', 0b10000110),
-        ('F', 0b10001110),
-        ('G', 0b11000010),
-        ('H', 0b10001001),
-        ('I', 0b11001111),
-        ('J', 0b11100001),
-        ('K', 0b10001010),
-        ('L', 0b11000111),
-        ('M', 0b11101010),
-        ('N', 0b11001000),
-        ('O', 0b11000000),
-        ('P', 0b10001100),
-        ('Q', 0b10010100),
-        ('R', 0b11001100),
-        ('S', 0b10010010),
-        ('T', 0b11001110),
-        ('U', 0b11000001),
-        ('V', 0b11000001),
-        ('W', 0b11010101),
-        ('Y', 0b10010001),
-        ('Z', 0b10100100),
-        ('0', 0b11000000),
-        ('1', 0b11111001),
-        ('2', 0b10100100),
-        ('3', 0b10110000),
-        ('4', 0b10011001),
-        ('5', 0b10010010),
-        ('6', 0b10000010),
-        ('7', 0b11111000),
-        ('8', 0b10000000),
-        ('9', 0b10010000),
-        (']', 0b11110000),
-        ('=', 0b11110110),
-        ('K', 0b10000101),
-        ('-', 0b10111111),
-    ];
-    let mut i = 0;
-    while i < vals.len() {
-        let (c, j) = vals[i as usize];
-        res[j as usize] = c;
-        res[(j & 0x7f) as usize] = c;
-        i += 1;
-    }
-    res
-}
-const LCD_MAP: [char; 0x100] = calc_lcd_map();
-const _LED_NAMES: [&str; 8] = [
-    "black_led",
-    "white_led",
-    "calc_led",
-    "mem_led",
-    "pos_led",
-    "play_led",
-    "play_tone",
-    "strobe_lcd",
-];
-#[allow(dead_code)]
-#[derive(Clone, Copy)]
+use w65c02s::W65C02S;
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug)]
 enum MM2Button {
     CL = 0,
     POS,
@@ -122,6 +41,56 @@ enum MM2Button {
     D4Rook,
 }
 
+// the firmware has to see an input in this many timer interrupts to take it
+const INPUT_SCANS: u32 = 4;
+const INPUT_TIMEOUT: Duration = Duration::from_secs(1);
+// how long we give the firmware to react to a key
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+const SETTLE_IRQS: u64 = 8;
+// how often we look at the machine while it thinks
+const POLL_TIME: Duration = Duration::from_millis(100);
+// how often the search is reported while the machine thinks
+const INFO_INTERVAL: Duration = Duration::from_millis(500);
+// how long we follow the leds before giving up on them
+const LED_TIMEOUT: Duration = Duration::from_secs(5);
+// how often a search is started over after the firmware made an illegal move
+const SEARCH_ATTEMPTS: u32 = 3;
+
+// the keys for levels 1 to 10, which are also the digits 1 to 9 and 0
+const LEVEL_KEYS: [MM2Button; 10] = [
+    MM2Button::A1Pawn,
+    MM2Button::B2Knight,
+    MM2Button::C3Bishop,
+    MM2Button::D4Rook,
+    MM2Button::E5Queen,
+    MM2Button::F6King,
+    MM2Button::G7,
+    MM2Button::H8,
+    MM2Button::LeftBlack9,
+    MM2Button::RightWhite0,
+];
+// the most moves the mate level takes
+const MAX_MATE_MOVES: u8 = 9;
+// Elo, level and the most milliseconds a move may take for UCI_Elo. Not
+// calibrated: a rough guess from the MM II's published rating at tournament
+// level and the times of the lower levels, not measured with this emulator.
+const ELO_LEVELS: [(u16, u8, Option<u64>); 10] = [
+    (1000, 1, Some(500)),
+    (1150, 1, Some(2000)),
+    (1300, 1, None),
+    (1400, 2, None),
+    (1500, 3, None),
+    (1600, 4, None),
+    (1700, 5, None),
+    (1800, 6, None),
+    (1900, 7, None),
+    (1950, 8, None),
+];
+pub const MIN_ELO: u16 = ELO_LEVELS[0].0;
+pub const MAX_ELO: u16 = ELO_LEVELS[ELO_LEVELS.len() - 1].0;
+
+const START_BITBOARD: [u8; 8] = [0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0];
+
 const PIECE_BUTTONS: [MM2Button; 6] = [
     MM2Button::A1Pawn,
     MM2Button::B2Knight,
@@ -132,205 +101,241 @@ const PIECE_BUTTONS: [MM2Button; 6] = [
 ];
 
 pub trait MephistoEmu {
-    fn set_difficulty(self: &mut Self, new_difficulty: Option<u8>) -> Result<(), Error>;
-    fn set_position(self: &mut Self, startpos: bool, fen: Option<UciFen>, movs: Vec<ChessMove>);
-    fn set_fen(self: &mut Self, fen: &str);
-    fn force_moves(self: &mut Self, movs: Vec<ChessMove>);
-    fn play_move(self: &mut Self, mov: ChessMove);
+    fn name(&self) -> String;
+    fn virtual_now(&self) -> Duration;
+    fn init(&mut self);
+    fn default_crystal_hz(&self) -> u64;
+    fn set_crystal_hz(&mut self, crystal_hz: u64);
+    fn set_clock_policy(&mut self, policy: ClockPolicy);
+    fn save_state(&self) -> MachineState;
+    fn load_state(&mut self, state: &MachineState) -> Result<(), Error>;
+    fn load_game(&mut self, state: &MachineState) -> Result<(), Error>;
+    fn set_difficulty(&mut self, new_difficulty: Option<u8>) -> Result<(), Error>;
+    fn limit_strength(&mut self, elo: Option<u16>) -> Result<(), Error>;
+    fn set_position(&mut self, startpos: bool, fen: Option<UciFen>, movs: Vec<ChessMove>);
+    fn game(&self) -> (String, Vec<ChessMove>);
+    fn set_fen(&mut self, fen: &str);
+    fn force_moves(&mut self, movs: Vec<ChessMove>);
+    fn play_move(&mut self, mov: ChessMove);
     fn gen_move(
-        self: &mut Self,
+        &mut self,
+        rec: &Receiver<UciMessage>,
+        time_control: Option<UciTimeControl>,
+    ) -> Option<UciMessage>;
+    fn solve_mate(
+        &mut self,
         rec: &Receiver<UciMessage>,
+        moves: u8,
         time_control: Option<UciTimeControl>,
     ) -> Option<UciMessage>;
 }
 
-pub struct MM2Emu {
+pub fn new_emu(
+    module: &str,
+    library: &str,
+    roms: &RomLoader,
+) -> Result<Box<dyn MephistoEmu>, Error> {
+    Ok(Box::new(ModularEmu::new(Module::by_name(
+        module, library, roms,
+    )?)))
+}
+
+// Drives any board of the Modular series through its keypad and sensor board.
+pub struct ModularEmu {
     cpu: W65C02S,
-    pub system: MM2,
+    module: Module,
+    pub hw: Hardware,
     pub cur_board: Board,
-    instruction_count: u64,
+    timing: Timing,
+    throttle: Throttle,
+    next_irq: u64,
     interrupt_count: u64,
     difficulty: u8,
     tone_count: u64,
     last_move_forced: bool,
-    last_movs: Option<Vec<ChessMove>>,
+    game_start: String,
+    game_movs: Vec<ChessMove>,
+    boot_states: HashMap<(u8, [u8; 8], bool), MachineState>,
+    board_layouts: Option<Vec<BoardLayout>>,
+    move_slots: Option<Vec<MoveSlots>>,
+    score_slots: Option<Vec<ValueSlot>>,
+    depth_slots: Option<Vec<ValueSlot>>,
+    // thinking on the opponent's time, the move is held back until the ponder hit
+    pondering: bool,
+    // go infinite, the move is held back until stop
+    analysing: bool,
+    // the machine before the last move we played into it, to take that back
+    before_last_move: Option<MachineState>,
+    // the mate the firmware announced during the last search
+    mate_found: Option<i8>,
+    // how long a move may take at the limited strength
+    move_cap: Option<Duration>,
 }
 
-impl MM2Emu {
-    pub fn new() -> MM2Emu {
-        MM2Emu {
+impl ModularEmu {
+    pub fn new(module: Module) -> ModularEmu {
+        ModularEmu {
             cpu: W65C02S::new(),
-            system: MM2::new(),
+            hw: Hardware::new(module.ram_size),
             cur_board: Board::default(),
-            instruction_count: 0,
+            timing: module.timing,
+            module,
+            throttle: Throttle::new(ClockPolicy::Fast),
+            next_irq: 0,
             interrupt_count: 0,
             difficulty: 1,
             // key_pressed: 16,
             tone_count: 0,
             last_move_forced: false,
-            last_movs: None,
+            game_start: "startpos".to_string(),
+            game_movs: Vec::new(),
+            boot_states: HashMap::new(),
+            board_layouts: None,
+            move_slots: None,
+            score_slots: None,
+            depth_slots: None,
+            pondering: false,
+            analysing: false,
+            before_last_move: None,
+            mate_found: None,
+            move_cap: None,
         }
     }
-    fn await_interrupt(self: &mut MM2Emu) {
-        while self.instruction_count < 2000 {
-            self.cpu.step(&mut self.system);
-            self.instruction_count += 1;
-            if self.system.outlatch[6] {
-                self.tone_count += 1;
-            }
+    fn step(&mut self) {
+        self.cpu.step(&mut Bus {
+            hw: &mut self.hw,
+            module: &self.module,
+        });
+        if self.hw.outlatch[6] {
+            self.tone_count += 1;
         }
-        self.instruction_count = 0;
+    }
+    fn await_interrupt(&mut self) {
+        while self.hw.cycles < self.next_irq {
+            self.step();
+        }
+        self.next_irq += self.timing.irq_period();
         self.interrupt_count += 1;
+        self.throttle.pace(&self.timing, self.hw.cycles);
         self.cpu.set_irq(true);
-        self.system.irq_done = false;
-        while !self.system.irq_done {
-            self.cpu.step(&mut self.system);
-            if self.system.outlatch[6] {
-                self.tone_count += 1;
-            }
+        self.hw.irq_done = false;
+        while !self.hw.irq_done {
+            self.step();
         }
     }
-    fn wait_1sec(self: &mut MM2Emu) {
-        self.interrupt_count = 0;
-        for _ in 0..500 {
+    fn run_cycles(&mut self, cycles: u64) {
+        let end = self.hw.cycles + cycles;
+        while self.hw.cycles < end {
             self.await_interrupt();
         }
     }
-    pub fn init(self: &mut MM2Emu) {
-        self.cpu.reset();
-        self.system.display_pos = 3;
-        self.system.board_leds_big.copy_from_slice(&[0 as u8; 64]);
-        self.wait_1sec();
-        self.wait_1sec();
-    }
-    fn press_key(self: &mut MM2Emu, button: MM2Button) {
-        self.wait_1sec();
-        let key_pressed = button as usize;
-        self.system.pressed_keys[(key_pressed > 7) as usize][key_pressed % 8] = true;
-        self.wait_1sec();
-        let key_pressed = button as usize;
-        self.system.pressed_keys[(key_pressed > 7) as usize][key_pressed % 8] = false;
-        self.wait_1sec();
-    }
-    fn set_default_pos(self: &mut MM2Emu) {
-        self.system.cur_bitboard = [0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0];
-        self.cur_board = Board::default();
-        self.init();
-        self.set_difficulty(None).unwrap();
-    }
-    fn make_half_move(self: &mut MM2Emu, sq: chess::Square) {
-        // for (i, v) in (0..).zip(self.system.board_leds_big) {
-        //     if i % 8 == 0 {
-        //         println!("preboard {i} {}", self.system.outlatch[7]);
-        //     }
-        //     print!("{v:02x} ");
-        // }
-        // println!();
-        self.wait_1sec();
-        self.system.cur_bitboard[sq.get_rank().to_index()] ^= 1 << (sq.get_file().to_index());
-        self.wait_1sec();
-        // for (i, v) in (0..).zip(self.system.board_leds_big) {
-        //     if i % 8 == 0 {
-        //         println!("postboard {i} {}", self.system.outlatch[7]);
-        //     }
-        //     print!("{v:02x} ");
-        // }
-        // println!();
+    fn wait_1sec(&mut self) {
+        self.interrupt_count = 0;
+        self.run_cycles(self.timing.crystal_hz);
     }
-}
-
-impl MephistoEmu for MM2Emu {
-    fn set_difficulty(self: &mut MM2Emu, new_difficulty: Option<u8>) -> Result<(), Error> {
-        if let Some(diff) = new_difficulty {
-            if diff < 1 || diff > 10 {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Difficulty can only be from 1 to 10!",
-                ));
+    // Runs the machine until `cond` holds, checking once per timer interrupt.
+    // Returns false if it still didn't after `timeout` of emulated time.
+    fn run_until(&mut self, timeout: Duration, mut cond: impl FnMut(&Hardware) -> bool) -> bool {
+        let end = self.hw.cycles + self.timing.duration_to_cycles(timeout);
+        loop {
+            if cond(&self.hw) {
+                return true;
             }
-            self.difficulty = diff - 1;
+            if self.hw.cycles >= end {
+                return false;
+            }
+            self.await_interrupt();
         }
-        const DIFFICULTIES: [MM2Button; 10] = [
-            MM2Button::A1Pawn,
-            MM2Button::B2Knight,
-            MM2Button::C3Bishop,
-            MM2Button::D4Rook,
-            MM2Button::E5Queen,
-            MM2Button::F6King,
-            MM2Button::G7,
-            MM2Button::H8,
-            MM2Button::LeftBlack9,
-            MM2Button::RightWhite0,
-        ];
-        self.press_key(MM2Button::LEV);
-        self.press_key(DIFFICULTIES[(self.difficulty) as usize]);
-        self.press_key(MM2Button::ENT);
-        Ok(())
     }
-    fn set_position(self: &mut Self, startpos: bool, fen: Option<UciFen>, movs: Vec<ChessMove>) {
-        if self.last_movs.clone().unwrap_or_default() == movs {
-            self.last_movs = None;
-        } else {
-            self.last_movs = Some(movs.clone());
+    fn wait_irqs(&mut self, count: u64) {
+        let target = self.interrupt_count + count;
+        while self.interrupt_count < target {
+            self.await_interrupt();
         }
-        if startpos && movs.len() == 0 {
-            self.set_default_pos();
-            return;
-        } else if startpos && movs.len() == 1 {
-            self.set_default_pos();
-            self.play_move(movs[0]);
-            return;
+    }
+    fn wait_display_change(&mut self, timeout: Duration) -> bool {
+        let display = self.hw.display;
+        self.run_until(timeout, |sys| sys.display != display)
+    }
+    fn wait_led_change(&mut self, timeout: Duration) -> bool {
+        let led = self.hw.led_square;
+        self.run_until(timeout, |sys| sys.led_square != led)
+    }
+    // Waits for the leds to move on while the machine shows its move. If they
+    // don't, the game is put back into the firmware to search again.
+    fn next_led(&mut self) -> bool {
+        if self.wait_led_change(LED_TIMEOUT) {
+            return true;
         }
-        let nfen: &str;
-        let s: UciFen;
-        let mb_last = movs.last();
-        let last: ChessMove = *mb_last.unwrap();
-        if self.cur_board.legal(last) {
-            let nb = self.cur_board.make_move_new(last);
-            let mut ob = if startpos {
-                nfen = "startpos";
-                Board::default()
-            } else {
-                s = fen.unwrap();
-                nfen = s.as_str();
-                Board::from_str(nfen).unwrap()
-            };
-            for mov in movs.iter() {
-                ob = ob.make_move_new(*mov);
+        out!("info Debug lost track of the leds, retrying from last position!");
+        self.resync();
+        false
+    }
+    // waits until the firmware has looked at a counter in `scans` different
+    // timer interrupts
+    fn wait_scans(
+        &mut self,
+        scans: u32,
+        timeout: Duration,
+        polls: impl Fn(&Hardware) -> u64,
+    ) -> bool {
+        let mut last = polls(&self.hw);
+        let mut seen = 0;
+        self.run_until(timeout, |sys| {
+            if polls(sys) > last {
+                last = polls(sys);
+                seen += 1;
             }
-            if nb == ob {
-                self.play_move(last);
-                return;
+            seen >= scans
+        })
+    }
+    // Holds the key until the firmware has scanned it a few times, then lets
+    // go the same way and gives the firmware a moment to act on it.
+    fn press_key(&mut self, button: MM2Button) {
+        let key_pressed = button as usize;
+        let (bank, row) = ((key_pressed > 7) as usize, key_pressed % 8);
+        for pressed in [true, false] {
+            self.hw.pressed_keys[bank][row] = pressed;
+            if !self.wait_scans(INPUT_SCANS, INPUT_TIMEOUT, |sys| sys.key_polls[bank][row]) {
+                out!("info Debug key {button:?} was not scanned in time");
             }
-        } else if startpos {
-            nfen = "startpos"
-        } else {
-            s = fen.unwrap();
-            nfen = s.as_str();
         }
-        self.set_fen(nfen);
-        self.force_moves(movs);
+        self.wait_display_change(SETTLE_TIME);
     }
-    fn set_fen(self: &mut MM2Emu, fen: &str) {
-        if fen == "startpos" {
-            return self.set_default_pos();
+    // Boots the machine with the given pieces on the sensor board. Booting and
+    // selecting a level takes several emulated seconds, so the result is kept
+    // and restored the next time the same boot is asked for.
+    fn warm_start(&mut self, bitboard: [u8; 8], set_level: bool) {
+        let key = (self.difficulty, bitboard, set_level);
+        if let Some(state) = self.boot_states.get(&key).cloned() {
+            self.load_state(&state).unwrap();
+            return;
         }
-        let board = match Board::from_str(fen) {
-            Ok(b) => b,
-            Err(e) => {
-                println!(
-                    "info Debug invalid fen: {fen}, Error: {e}\ninfo Debug using default Board!"
-                );
-                Board::default()
-            }
-        };
-        self.system.cur_bitboard = [0; 8];
-        self.cur_board = board;
+        self.hw.cur_bitboard = bitboard;
         self.init();
+        if set_level {
+            self.set_difficulty(None).unwrap();
+        }
+        self.boot_states.insert(key, self.save_state());
+    }
+    fn begin_position_entry(&mut self) {
+        self.warm_start([0; 8], false);
         self.press_key(MM2Button::POS);
         self.press_key(MM2Button::ENT);
-        self.wait_1sec();
-        println!("info Debug cur board: {}", board);
+        self.wait_display_change(SETTLE_TIME);
+    }
+    fn end_position_entry(&mut self, board: &Board) {
+        self.press_key(MM2Button::CL);
+        if board.side_to_move() == Color::Black {
+            self.press_key(MM2Button::POS);
+            self.press_key(MM2Button::LeftBlack9);
+            self.press_key(MM2Button::CL);
+        }
+    }
+    // places every piece with the piece keys and the sensor board, like a
+    // human would
+    fn enter_position(&mut self, board: &Board) {
+        self.begin_position_entry();
         let mut last_piece = None;
         let mut last_color = None;
         for f in 0..8 {
@@ -351,7 +356,7 @@ impl MephistoEmu for MM2Emu {
                     }
                     last_piece = Some(piece);
                     last_color = Some(color);
-                    println!(
+                    out!(
                         "info Debug placing {} {} on {}",
                         if color == Color::White {
                             "white"
@@ -362,474 +367,1073 @@ impl MephistoEmu for MM2Emu {
                         sq
                     );
                     self.make_half_move(sq);
-                    self.wait_1sec();
                 }
             }
         }
-        self.press_key(MM2Button::CL);
-        if board.side_to_move() == Color::Black {
-            self.press_key(MM2Button::POS);
-            self.press_key(MM2Button::LeftBlack9);
-            self.press_key(MM2Button::CL);
+        self.end_position_entry(board);
+    }
+    // Looks for the firmware's board in RAM once and leaves the machine as it
+    // was found.
+    fn board_layouts(&mut self) -> Vec<BoardLayout> {
+        if self.board_layouts.is_none() {
+            let saved = self.save_state();
+            self.warm_start(START_BITBOARD, true);
+            let candidates = BoardLayout::discover(&self.hw.ram, &Board::default());
+            // the start position looks the same upside down with the colors
+            // swapped, so play 1. e4 to tell those apart
+            let after =
+                Board::default().make_move_new(ChessMove::new(Square::E2, Square::E4, None));
+            self.make_half_move(Square::E2);
+            self.make_half_move(Square::E4);
+            self.wait_display_change(SETTLE_TIME);
+            let found = candidates
+                .into_iter()
+                .filter(|l| {
+                    // the firmware may already have answered from its library
+                    l.holds(&self.hw.ram, &after)
+                        || MoveGen::new_legal(&after)
+                            .any(|m| l.holds(&self.hw.ram, &after.make_move_new(m)))
+                })
+                .collect::<Vec<BoardLayout>>();
+            for l in found.iter() {
+                out!(
+                    "info Debug found board in RAM at {:04X}, rank stride {}{}",
+                    l.base,
+                    l.rank_stride,
+                    if l.flipped { ", flipped" } else { "" }
+                );
+            }
+            if found.is_empty() {
+                out!("info string could not find the board in RAM, a lost sync will go unnoticed");
+            }
+            self.board_layouts = Some(found);
+            self.load_state(&saved).unwrap();
         }
+        self.board_layouts.clone().unwrap()
     }
-    fn force_moves(self: &mut Self, movs: Vec<ChessMove>) {
-        self.press_key(MM2Button::LEV);
-        self.press_key(MM2Button::MEM);
-        self.press_key(MM2Button::ENT);
-        for mov in movs {
-            self.play_move(mov);
+    // the color leds show whose turn it is
+    fn firmware_side_to_move(&self) -> Option<Color> {
+        match (self.hw.outlatch[1], self.hw.outlatch[0]) {
+            (true, false) => Some(Color::White),
+            (false, true) => Some(Color::Black),
+            _ => None,
         }
-        self.last_move_forced = true;
     }
-    fn play_move(self: &mut MM2Emu, mov: ChessMove) {
-        if !self.cur_board.legal(mov) {
-            panic!(
-                "info Debug Trying invalid move({})! cur_board: {}",
-                mov, self.cur_board
-            )
+    // The position as the firmware sees it, decoded from its RAM. None if the
+    // board couldn't be found or holds something that isn't a legal position.
+    pub fn firmware_board(&mut self) -> Option<Board> {
+        let layout = *self.board_layouts().first()?;
+        let side = self
+            .firmware_side_to_move()
+            .unwrap_or(self.cur_board.side_to_move());
+        to_board(&layout.read(&self.hw.ram)?, side)
+    }
+    // firmware_board for the log, saying which of its fields are guesses
+    fn firmware_fen(&mut self) -> String {
+        match self.firmware_board() {
+            Some(b) => format!("{b} (castling guessed from the home squares, en passant unknown)"),
+            None => "an unreadable board".to_string(),
         }
-        // remove piece at dest before making move
-        if self.cur_board.piece_on(mov.get_dest()).is_some() {
-            self.make_half_move(mov.get_dest());
+    }
+    // Narrows down where the firmware keeps its chosen move and expected reply,
+    // given the RAM at the time the move was shown and what the display said.
+    fn learn_move_slots(&mut self, ram: &[u8], best: ChessMove, ponder: ChessMove) {
+        let layout = match self.board_layouts().first() {
+            Some(l) => *l,
+            None => return,
+        };
+        let known = self.move_slots.as_ref().is_some_and(|c| c.len() == 1);
+        let candidates = match self.move_slots.take() {
+            None => MoveSlots::discover(ram, &layout, best, ponder),
+            Some(c) => c
+                .into_iter()
+                .filter(|s| s.matches(ram, &layout, best, ponder))
+                .collect(),
+        };
+        if candidates.len() == 1 && !known {
+            out!(
+                "info Debug found move variables in RAM at {:04X}/{:04X}",
+                candidates[0].from,
+                candidates[0].to
+            );
+        } else if candidates.is_empty() && known {
+            out!("info Debug move variables in RAM did not hold up, relearning");
         }
-        // remove en passant piece
-        if let Some(passant) = self.cur_board.en_passant() {
-            self.cur_board = self.cur_board.make_move_new(mov);
-            if self.cur_board.piece_on(passant) == None {
-                self.make_half_move(passant);
-            }
+        self.move_slots = if candidates.is_empty() {
+            None
         } else {
-            self.cur_board = self.cur_board.make_move_new(mov);
+            Some(candidates)
+        };
+    }
+    // The firmware's chosen move and the reply it expects, read from RAM once
+    // we know where to look.
+    fn ram_result(&mut self, promotion: Option<Piece>) -> Option<(ChessMove, Option<ChessMove>)> {
+        let slots = match self.move_slots.as_deref() {
+            Some([slots]) => *slots,
+            _ => return None,
+        };
+        let layout = *self.board_layouts().first()?;
+        let (best, ponder) = slots.read(&self.hw.ram, &layout);
+        let best = legal_move(&self.cur_board, best?, promotion)?;
+        let after = self.cur_board.make_move_new(best);
+        Some((best, ponder.and_then(|p| legal_move(&after, p, None))))
+    }
+    // Writes the pieces straight into the firmware's board while it is in
+    // position mode and lets it pick them up when leaving it. Returns false if
+    // the firmware didn't end up with the position. Only the array that is
+    // seen holding the current game is written, any other one that looked like
+    // a board at boot may be used for something else by now.
+    fn inject_position(&mut self, board: &Board) -> bool {
+        let cur_board = self.cur_board;
+        let layouts = self.board_layouts();
+        // a search moves pieces around on the firmware's board for a while
+        let mut layout = None;
+        self.run_until(Duration::from_millis(500), |sys| {
+            layout = layouts
+                .iter()
+                .find(|l| l.holds(&sys.ram, &cur_board))
+                .copied();
+            layout.is_some()
+        });
+        let Some(layout) = layout else {
+            return false;
+        };
+        self.begin_position_entry();
+        layout.write(&mut self.hw.ram, board);
+        // leave the sensors as placing the pieces by hand would
+        for sq in *board.combined() {
+            self.hw.cur_bitboard[sq.get_rank().to_index()] ^= 1 << sq.get_file().to_index();
         }
-        self.make_half_move(mov.get_source());
-        self.make_half_move(mov.get_dest());
-        self.tone_count = 0;
-        // check casteling
-        if mov.get_source().get_file() == File::E
-            && self.cur_board.piece_on(mov.get_dest()).unwrap() == Piece::King
-        {
-            if mov.get_dest().get_file() == File::G || mov.get_dest().get_file() == File::C {
-                let rank = mov.get_source().get_rank();
-                let sec_mov;
-                if mov.get_dest().get_file() == chess::File::G {
-                    sec_mov = ChessMove::new(
-                        Square::make_square(rank, File::H),
-                        Square::make_square(rank, File::F),
-                        None,
-                    );
-                } else {
-                    sec_mov = ChessMove::new(
-                        Square::make_square(rank, File::A),
-                        Square::make_square(rank, File::D),
-                        None,
-                    );
-                }
-                self.make_half_move(sec_mov.get_source());
-                self.make_half_move(sec_mov.get_dest());
+        self.end_position_entry(board);
+        self.wait_irqs(SETTLE_IRQS);
+        if !layout.holds(&self.hw.ram, board) {
+            out!("info Debug firmware did not accept the injected position");
+            return false;
+        }
+        true
+    }
+    // Sets up `board`, injected into RAM where that gives the firmware the
+    // right castling rights and by hand otherwise.
+    fn set_board(&mut self, board: &Board) {
+        if !rights_implied(board) {
+            out!("info Debug castling rights can't be written to RAM, entering position by hand");
+            self.enter_position(board);
+        } else if !self.inject_position(board) {
+            out!("info Debug entering position by hand");
+            self.enter_position(board);
+        }
+        self.cur_board = *board;
+    }
+    // Plays just the last move if the rest of the game is what we already have.
+    fn continue_game(&mut self, start: &str, movs: &[ChessMove]) -> bool {
+        let last = match movs.last() {
+            Some(m) if self.cur_board.legal(*m) => *m,
+            _ => return false,
+        };
+        let mut target = if start == "startpos" {
+            Board::default()
+        } else {
+            match Board::from_str(start) {
+                Ok(b) => b,
+                Err(_) => return false,
             }
+        };
+        for mov in movs.iter() {
+            target = target.make_move_new(*mov);
         }
-        if mov.get_promotion().is_some() {
-            let prom = mov.get_promotion().unwrap();
-            self.press_key(PIECE_BUTTONS[prom as usize])
+        if self.cur_board.make_move_new(last) != target {
+            return false;
         }
+        self.before_last_move = Some(self.save_state());
+        self.play_move(last);
+        true
     }
-    fn gen_move(
-        self: &mut MM2Emu,
-        rec: &Receiver<UciMessage>,
-        time_control: Option<UciTimeControl>,
-    ) -> Option<UciMessage> {
-        let end_time: Option<Instant> = if let Some(tc) = time_control {
+    // After a ponder miss the machine is a move or two ahead of the game, so
+    // going back to before the guessed move usually spares a full replay.
+    fn take_back(&mut self, start: &str, movs: &[ChessMove]) -> bool {
+        match self.before_last_move.take() {
+            Some(state) => self.load_state(&state).is_ok() && self.continue_game(start, movs),
+            None => false,
+        }
+    }
+    fn replay(&mut self, start: &str, movs: &[ChessMove]) {
+        self.before_last_move = None;
+        self.set_fen(start);
+        if !movs.is_empty() {
+            self.force_moves(movs.to_vec());
+        }
+    }
+    fn in_sync(&mut self) -> bool {
+        let cur_board = self.cur_board;
+        let layouts = self.board_layouts();
+        layouts.is_empty() || layouts.iter().any(|l| l.holds(&self.hw.ram, &cur_board))
+    }
+    // Checks that the firmware still has the game we think it has and replays
+    // it from the last position command if not. The firmware may already be
+    // searching, which moves pieces around on its board, so we keep looking
+    // for half a second before calling it lost.
+    fn check_sync(&mut self) {
+        let cur_board = self.cur_board;
+        let layouts = self.board_layouts();
+        if layouts.is_empty()
+            || self.run_until(Duration::from_millis(500), |sys| {
+                layouts.iter().any(|l| l.holds(&sys.ram, &cur_board))
+            })
+        {
+            return;
+        }
+        let seen = self.firmware_fen();
+        out!(
+            "info string firmware has {} instead of {}, replaying {} moves from {}",
+            seen,
+            self.cur_board,
+            self.game_movs.len(),
+            self.game_start
+        );
+        self.resync();
+    }
+    fn resync(&mut self) {
+        let (start, movs) = (self.game_start.clone(), self.game_movs.clone());
+        self.replay(&start, &movs);
+        if !self.in_sync() {
+            out!("info string could not bring the firmware back in sync");
+        }
+    }
+    // bookkeeping for a move the engine made
+    fn engine_moved(&mut self, mov: ChessMove) {
+        self.game_movs.push(mov);
+        self.check_sync();
+    }
+    // When the move has to be made, in emulated time scaled by the clock policy.
+    fn deadline(&self, time_control: Option<UciTimeControl>) -> Option<Duration> {
+        let scale = self.throttle.policy().scale();
+        if let Some(tc) = time_control {
             match tc {
                 UciTimeControl::MoveTime(time) => match time.to_std() {
-                    Ok(dur) => Some(Instant::now() + dur),
+                    Ok(dur) => Some(self.virtual_now() + dur * scale),
                     Err(_) => {
-                        println!("info Debug could not parse movetime");
+                        out!("info Debug could not parse movetime");
                         None
                     }
                 },
                 UciTimeControl::Infinite => None,
-                _ => {
-                    println!("info Debug only movetime time control accepted");
-                    None
+                UciTimeControl::TimeLeft {
+                    white_time,
+                    black_time,
+                    white_increment,
+                    black_increment,
+                    moves_to_go,
+                } => {
+                    let (left, inc) = match self.cur_board.side_to_move() {
+                        Color::White => (white_time, white_increment),
+                        Color::Black => (black_time, black_increment),
+                    };
+                    match left {
+                        Some(left) => {
+                            // a flagged clock comes in negative, that's no time at all
+                            let left = left.to_std().unwrap_or_default();
+                            let inc = inc.and_then(|i| i.to_std().ok()).unwrap_or_default();
+                            let budget = move_budget(left, inc, moves_to_go.map(u32::from));
+                            out!("info Debug thinking for {}ms", budget.as_millis());
+                            Some(self.virtual_now() + budget * scale)
+                        }
+                        None => {
+                            out!("info Debug no clock for the side to move");
+                            None
+                        }
+                    }
                 }
+                // no deadline until the ponder hit
+                UciTimeControl::Ponder => None,
             }
         } else {
             None
-        };
-        loop {
-            match rec.try_recv() {
-                Ok(message) => match message {
-                    UciMessage::IsReady => println!("{}", UciMessage::ReadyOk),
-                    UciMessage::Stop => self.press_key(MM2Button::ENT),
-                    UciMessage::Quit => std::process::exit(0),
-                    _ => {
-                        println!(
-                            "info Debug ignoring unknown UCI message during search: {}",
-                            message
-                        )
+        }
+    }
+    // When an emulated deadline is due on the wall clock. A host that can't
+    // keep up with the clock policy would otherwise overstep the gui's time.
+    fn wall_deadline(&self, end_time: Option<Duration>) -> Option<Instant> {
+        let scale = self.throttle.policy().scale();
+        end_time.map(|et| Instant::now() + et.saturating_sub(self.virtual_now()) / scale)
+    }
+    // Waits for the machine to come up with its move, forcing it with ENT on
+    // stop or once end_time has passed, in emulated or wall time. An illegal move puts the game back
+    // into the firmware and the search starts over, SEARCH_ATTEMPTS times
+    // before we give up with no move.
+    fn search(
+        &mut self,
+        rec: &Receiver<UciMessage>,
+        mut end_time: Option<Duration>,
+    ) -> Option<UciMessage> {
+        let started = self.virtual_now();
+        let mut next_info = started + INFO_INTERVAL;
+        let mut wall_end = self.wall_deadline(end_time);
+        'attempt: for _ in 0..SEARCH_ATTEMPTS {
+            loop {
+                match rec.try_recv() {
+                    Ok(message) => match message {
+                        UciMessage::IsReady => out!("{}", UciMessage::ReadyOk),
+                        // the ponder hit, uci::spawn_stdin_channel sends it as a go
+                        // with the clock of the go ponder
+                        UciMessage::Go { time_control, .. } if self.pondering => {
+                            self.pondering = false;
+                            end_time = self.deadline(time_control);
+                            wall_end = self.wall_deadline(end_time);
+                        }
+                        UciMessage::Stop => {
+                            self.pondering = false;
+                            self.analysing = false;
+                            self.press_key(MM2Button::ENT);
+                        }
+                        UciMessage::Quit => std::process::exit(0),
+                        _ => {
+                            out!(
+                                "info Debug ignoring unknown UCI message during search: {}",
+                                message
+                            )
+                        }
+                    },
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => {
+                        panic!("Stdin disconnected!")
                     }
-                },
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Stdin disconnected!")
                 }
-            }
-            if let Some(et) = end_time {
-                if Instant::now() >= et {
-                    // finish move if we are out of time
-                    println!("Stopping calc due to movetime!");
-                    self.press_key(MM2Button::ENT);
-                }
-            }
-            self.wait_1sec();
-            if !(self.system.outlatch[0] || self.system.outlatch[1]) {
-                continue;
-            }
-            let disp_str = self
-                .system
-                .display
-                .as_slice()
-                .iter()
-                .map(|a| LCD_MAP[*a as usize])
-                .collect::<String>();
-            if disp_str.starts_with(" N ") {
-                self.wait_1sec();
-                let num = disp_str.split_at(2 as usize).1.to_string();
-                let mate_in = Some(num.trim().parse::<i8>().unwrap());
-                println!(
-                    "{}",
-                    UciMessage::Info(vec![UciInfoAttribute::Score {
-                        cp: None,
-                        mate: mate_in,
-                        lower_bound: None,
-                        upper_bound: None
-                    }])
-                );
-                while self.cur_board.color_on(self.system.led_square).is_none() {
-                    self.wait_1sec();
+                if let (Some(et), Some(wall)) = (end_time, wall_end) {
+                    if self.virtual_now() >= et || Instant::now() >= wall {
+                        // finish move if we are out of time
+                        out!("Stopping calc due to movetime!");
+                        self.press_key(MM2Button::ENT);
+                    }
                 }
-                let mut start = self.system.led_square;
-                if self
-                    .cur_board
-                    .color_on(start)
-                    .is_some_and(|c| c != self.cur_board.side_to_move())
-                {
-                    self.make_half_move(self.system.led_square);
-                    while start == self.system.led_square {
-                        self.wait_1sec();
+                let (display, led) = (self.hw.display, self.hw.led_square);
+                self.run_until(POLL_TIME, |sys| {
+                    sys.display != display || sys.led_square != led
+                });
+                if !(self.hw.outlatch[0] || self.hw.outlatch[1]) {
+                    if self.virtual_now() >= next_info {
+                        self.report_progress(started);
+                        next_info += INFO_INTERVAL;
                     }
+                    continue;
                 }
+                let disp_str = self
+                    .hw
+                    .display
+                    .as_slice()
+                    .iter()
+                    .map(|a| LCD_MAP[*a as usize])
+                    .collect::<String>();
+                let messages = self.module.messages;
+                if let Some(num) = disp_str.strip_prefix(messages.mate) {
+                    self.wait_irqs(SETTLE_IRQS);
+                    let mate_in = num.trim().parse::<i8>().ok();
+                    self.mate_found = mate_in;
+                    match mate_in {
+                        Some(_) => out!(
+                            "{}",
+                            UciMessage::Info(vec![UciInfoAttribute::Score {
+                                cp: None,
+                                mate: mate_in,
+                                lower_bound: None,
+                                upper_bound: None
+                            }])
+                        ),
+                        None => out!("info Debug could not read the mate from {disp_str}"),
+                    }
+                    if let Some((m, ponder)) = self.ram_result(None) {
+                        self.play_move(m);
+                        self.engine_moved(m);
+                        return Some(UciMessage::BestMove {
+                            best_move: m,
+                            ponder,
+                        });
+                    }
+                    // follow the leds while making the move
+                    while self.cur_board.color_on(self.hw.led_square).is_none() {
+                        if !self.next_led() {
+                            continue 'attempt;
+                        }
+                    }
+                    let mut start = self.hw.led_square;
+                    if self
+                        .cur_board
+                        .color_on(start)
+                        .is_some_and(|c| c != self.cur_board.side_to_move())
+                    {
+                        self.make_half_move(self.hw.led_square);
+                        while start == self.hw.led_square {
+                            if !self.next_led() {
+                                continue 'attempt;
+                            }
+                        }
+                    }
 
-                start = self.system.led_square;
-                self.make_half_move(start);
+                    start = self.hw.led_square;
+                    self.make_half_move(start);
 
-                while start == self.system.led_square {
-                    self.wait_1sec();
-                }
-                let end = self.system.led_square;
-                let mut m = ChessMove::new(start, end, None);
+                    while start == self.hw.led_square {
+                        if !self.next_led() {
+                            continue 'attempt;
+                        }
+                    }
+                    let end = self.hw.led_square;
+                    let mut m = ChessMove::new(start, end, None);
 
-                self.make_half_move(self.system.led_square);
+                    self.make_half_move(self.hw.led_square);
 
-                if !self.cur_board.legal(m) {
-                    m = ChessMove::new(m.get_dest(), m.get_source(), None);
                     if !self.cur_board.legal(m) {
-                        if self.last_movs.is_none() {
-                            println!("info Debug failed to generate legal move, giving up!");
-                            return None;
-                        }
-                        println!("info Debug failed to generate legal move retrying from fen!");
-                        let ms = self.last_movs.clone().unwrap();
-                        self.set_position(true, None, ms);
-                        return self.gen_move(
-                            rec,
-                            match end_time {
-                                Some(et) => Some(UciTimeControl::MoveTime(
-                                    vampirc_uci::Duration::from_std(et - Instant::now())
-                                        .unwrap_or(vampirc_uci::Duration::min_value()),
-                                )),
-                                None => None,
-                            },
+                        m = ChessMove::new(m.get_dest(), m.get_source(), None);
+                        if !self.cur_board.legal(m) {
+                            out!("info Debug firmware board: {}", self.firmware_fen());
+                            out!(
+                            "info Debug failed to generate legal move, retrying from last position!"
                         );
+                            self.resync();
+                            continue 'attempt;
+                        }
                     }
-                }
-                let color = self.cur_board.side_to_move();
-                let old_castel = self.cur_board.castle_rights(color);
-                self.cur_board = self.cur_board.make_move_new(m);
-                if old_castel != self.cur_board.castle_rights(color)
-                    && (m.get_source() == Square::E1 || m.get_source() == Square::E8)
-                {
-                    // cleanup casteling leftovers
-                    let mut first = self.system.led_square;
-                    while first == end {
-                        self.wait_1sec();
-                        first = self.system.led_square;
+                    let color = self.cur_board.side_to_move();
+                    let old_castel = self.cur_board.castle_rights(color);
+                    self.cur_board = self.cur_board.make_move_new(m);
+                    if old_castel != self.cur_board.castle_rights(color)
+                        && (m.get_source() == Square::E1 || m.get_source() == Square::E8)
+                    {
+                        // cleanup casteling leftovers
+                        let mut first = self.hw.led_square;
+                        while first == end {
+                            if !self.next_led() {
+                                continue 'attempt;
+                            }
+                            first = self.hw.led_square;
+                        }
+                        self.make_half_move(first);
+                        while self.hw.led_square == first {
+                            if !self.next_led() {
+                                continue 'attempt;
+                            }
+                        }
+                        self.make_half_move(self.hw.led_square);
                     }
-                    self.make_half_move(first);
-                    while self.system.led_square == first {
-                        self.wait_1sec();
+                    self.engine_moved(m);
+                    return Some(UciMessage::BestMove {
+                        best_move: m,
+                        ponder: None,
+                    });
+                } else if disp_str.starts_with(messages.promotion) {
+                    let p_char = disp_str.chars().last().unwrap();
+                    let prom = match messages.promotion_pieces.iter().find(|(c, _)| *c == p_char) {
+                        Some((_, piece)) => *piece,
+                        None => {
+                            out!("info Debug unknown promotion {disp_str}, retrying from last position!");
+                            self.resync();
+                            continue 'attempt;
+                        }
+                    };
+                    if let Some((m, ponder)) = self.ram_result(Some(prom)) {
+                        self.play_move(m);
+                        self.engine_moved(m);
+                        return Some(UciMessage::BestMove {
+                            best_move: m,
+                            ponder,
+                        });
                     }
-                    self.make_half_move(self.system.led_square);
-                }
-                return Some(UciMessage::BestMove {
-                    best_move: m,
-                    ponder: None,
-                });
-            } else if disp_str.starts_with("Pr") {
-                let mut start = self.system.led_square;
-                if self.cur_board.color_on(start).unwrap() != self.cur_board.side_to_move() {
-                    // first remove taken piece
-                    self.make_half_move(start);
-                    while self.system.led_square == start {
-                        self.wait_1sec();
+                    // follow the leds while making the move
+                    let mut start = self.hw.led_square;
+                    if self.cur_board.color_on(start).unwrap() != self.cur_board.side_to_move() {
+                        // first remove taken piece
+                        self.make_half_move(start);
+                        while self.hw.led_square == start {
+                            if !self.next_led() {
+                                continue 'attempt;
+                            }
+                        }
+                        start = self.hw.led_square;
                     }
-                    start = self.system.led_square;
-                }
-                self.make_half_move(start);
-                let p_char = disp_str.chars().last().unwrap();
-                let prom = match p_char {
-                    'D' => Piece::Queen,
-                    'T' => Piece::Rook,
-                    '5' => Piece::Knight,
-                    'L' => Piece::Bishop,
-                    _ => panic!("Unknown Promotion"),
-                };
-                let m = ChessMove::new(start, self.system.led_square, Some(prom));
-                self.make_half_move(self.system.led_square);
-                self.press_key(PIECE_BUTTONS[prom as usize]);
+                    self.make_half_move(start);
+                    let m = ChessMove::new(start, self.hw.led_square, Some(prom));
+                    self.make_half_move(self.hw.led_square);
+                    self.press_key(PIECE_BUTTONS[prom as usize]);
 
-                self.cur_board = self.cur_board.make_move_new(m);
-                return Some(UciMessage::BestMove {
-                    best_move: m,
-                    ponder: None,
-                });
-            } else if disp_str == "PLAY" {
-                self.press_key(MM2Button::ENT);
-                continue;
-            } else if disp_str == "NAT " {
-                return None;
-            }
-            let mov = match ChessMove::from_str(disp_str.to_lowercase().as_str()) {
-                Ok(m) => m,
-                Err(_) => {
+                    self.cur_board = self.cur_board.make_move_new(m);
+                    self.engine_moved(m);
+                    return Some(UciMessage::BestMove {
+                        best_move: m,
+                        ponder: None,
+                    });
+                } else if disp_str == messages.play {
+                    self.press_key(MM2Button::ENT);
                     continue;
+                } else if disp_str == messages.no_move {
+                    return None;
+                }
+                let mov = match ChessMove::from_str(disp_str.to_lowercase().as_str()) {
+                    Ok(m) => m,
+                    Err(_) => {
+                        continue;
+                    }
+                };
+                let ram_at_move = self.hw.ram.to_vec();
+                let from_ram = self.ram_result(None);
+                if !self.cur_board.legal(mov) {
+                    out!("info Debug firmware shows {mov}, which isn't legal here, retrying from last position!");
+                    self.resync();
+                    continue 'attempt;
                 }
-            };
-            if self.cur_board.legal(mov) {
                 self.play_move(mov);
-            }
-            self.press_key(MM2Button::INFO);
-            let p_str = self
-                .system
-                .display
-                .iter()
-                .map(|a| LCD_MAP[*a as usize])
-                .collect::<String>()
-                .to_lowercase();
-            let p_move = match ChessMove::from_str(p_str.as_str()) {
-                Ok(m) => {
-                    if let Some(piece) = self.cur_board.piece_on(m.get_source()) {
-                        if piece == Piece::Pawn
-                            && (m.get_dest().get_rank() == Rank::First
-                                || m.get_dest().get_rank() == Rank::Eighth)
-                        {
-                            // set pawn promotion ponder
-                            Some(ChessMove::new(
-                                m.get_source(),
-                                m.get_dest(),
-                                Some(Piece::Queen),
-                            ))
+                self.press_key(MM2Button::INFO);
+                let p_str = self
+                    .hw
+                    .display
+                    .iter()
+                    .map(|a| LCD_MAP[*a as usize])
+                    .collect::<String>()
+                    .to_lowercase();
+                let p_move = match ChessMove::from_str(p_str.as_str()) {
+                    Ok(m) => {
+                        if let Some(piece) = self.cur_board.piece_on(m.get_source()) {
+                            if piece == Piece::Pawn
+                                && (m.get_dest().get_rank() == Rank::First
+                                    || m.get_dest().get_rank() == Rank::Eighth)
+                            {
+                                // set pawn promotion ponder
+                                Some(ChessMove::new(
+                                    m.get_source(),
+                                    m.get_dest(),
+                                    Some(Piece::Queen),
+                                ))
+                            } else {
+                                Some(m) // this could be nicer with if let chains :(
+                            }
                         } else {
-                            Some(m) // this could be nicer with if let chains :(
+                            Some(m)
                         }
-                    } else {
-                        Some(m)
                     }
+                    Err(_) => {
+                        out!("info Debug failed to parse ponder {p_str}!");
+                        None
+                    }
+                };
+                if let Some(p) = p_move {
+                    self.learn_move_slots(&ram_at_move, mov, p);
                 }
-                Err(_) => {
-                    println!("info Debug failed to parse ponder {p_str}!");
-                    None
+                let p_move = match from_ram {
+                    Some((m, ponder)) if m == mov && ponder.is_some() => ponder,
+                    _ => p_move,
+                };
+                // get score in centipawns
+                self.press_key(MM2Button::A1Pawn);
+                let mut info = self
+                    .hw
+                    .display
+                    .map(|a| {
+                        format!(
+                            "{}{}",
+                            LCD_MAP[a as usize],
+                            if a & 0x80 == 0 { "." } else { "" }
+                        )
+                    })
+                    .join("");
+                let score_shown = info.trim().parse::<f32>().ok();
+                let score = (score_shown.unwrap_or(0.0) * 100.0) as i32;
+                // get amount of bruteforced nodes
+                self.press_key(MM2Button::C3Bishop);
+                info = self
+                    .hw
+                    .display
+                    .iter()
+                    .map(|a| format!("{}", LCD_MAP[*a as usize]))
+                    .collect::<String>();
+                let vinfo = info.split(' ').collect::<Vec<&str>>();
+                let ninfo = if vinfo.len() > 1 { vinfo[1] } else { "0" };
+                let depth_shown = ninfo.trim().parse::<u8>().ok();
+                let nodes = match ninfo.trim().parse::<u8>() {
+                    Ok(n) => n,
+                    Err(e) => {
+                        out!("info Debug Could not parse: {} Error: {}", info, e);
+                        0
+                    }
+                };
+                self.press_key(MM2Button::CL);
+                // so they can be followed the next time the machine thinks
+                if score_shown.is_some() {
+                    self.score_slots = Self::learn_value_slots(
+                        "score",
+                        self.score_slots.take(),
+                        &ram_at_move,
+                        score,
+                    );
                 }
-            };
-            // get score in centipawns
-            self.press_key(MM2Button::A1Pawn);
-            let mut info = self
-                .system
-                .display
-                .map(|a| {
-                    format!(
-                        "{}{}",
-                        LCD_MAP[a as usize],
-                        if a & 0x80 == 0 { "." } else { "" }
-                    )
-                })
-                .join("");
-            let score = (match info.trim().parse::<f32>() {
-                Ok(f) => f,
-                Err(_) => 0.0,
-            } * 100.0) as i32;
-            // get amount of bruteforced nodes
-            self.press_key(MM2Button::C3Bishop);
-            info = self
-                .system
-                .display
-                .iter()
-                .map(|a| format!("{}", LCD_MAP[*a as usize]))
-                .collect::<String>();
-            let vinfo = info.split(' ').collect::<Vec<&str>>();
-            let ninfo = if vinfo.len() > 1 { vinfo[1] } else { "0" };
-            let nodes = match ninfo.trim().parse::<u8>() {
-                Ok(n) => n,
-                Err(e) => {
-                    println!("info Debug Could not parse: {} Error: {}", info, e);
-                    0
+                if let Some(depth) = depth_shown {
+                    self.depth_slots = Self::learn_value_slots(
+                        "depth",
+                        self.depth_slots.take(),
+                        &ram_at_move,
+                        depth as i32,
+                    );
                 }
-            };
-            self.press_key(MM2Button::CL);
-            println!(
-                "{}",
-                UciMessage::Info(vec![
-                    UciInfoAttribute::Score {
-                        cp: Some(score),
-                        mate: None,
-                        lower_bound: None,
-                        upper_bound: None
-                    },
-                    UciInfoAttribute::Depth(nodes)
-                ])
+                out!(
+                    "{}",
+                    UciMessage::Info(vec![
+                        UciInfoAttribute::Score {
+                            cp: Some(score),
+                            mate: None,
+                            lower_bound: None,
+                            upper_bound: None
+                        },
+                        UciInfoAttribute::Depth(nodes)
+                    ])
+                );
+                self.engine_moved(mov);
+                return Some(UciMessage::BestMove {
+                    best_move: mov,
+                    ponder: if p_move == Some(mov) { None } else { p_move },
+                });
+            }
+        }
+        // a move the firmware didn't choose would be ours, not the machine's
+        out!("info string firmware made no legal move in {SEARCH_ATTEMPTS} tries, giving up");
+        None
+    }
+    // Narrows down where a number from the INFO pages lives in RAM.
+    fn learn_value_slots(
+        name: &str,
+        candidates: Option<Vec<ValueSlot>>,
+        ram: &[u8],
+        value: i32,
+    ) -> Option<Vec<ValueSlot>> {
+        let known = candidates.as_ref().is_some_and(|c| c.len() == 1);
+        let candidates = match candidates {
+            None => ValueSlot::discover(ram, value),
+            Some(c) => c
+                .into_iter()
+                .filter(|s| s.read(ram) == Some(value))
+                .collect(),
+        };
+        if candidates.len() == 1 && !known {
+            out!(
+                "info Debug found the {name} in RAM at {:04X}",
+                candidates[0].addr
             );
-            return Some(UciMessage::BestMove {
-                best_move: mov,
-                ponder: if p_move == Some(mov) { None } else { p_move },
+        }
+        (!candidates.is_empty()).then_some(candidates)
+    }
+    // the root move the firmware has on its board right now, if it is just
+    // one move into the search
+    fn searched_move(&mut self) -> Option<ChessMove> {
+        let layouts = self.board_layouts();
+        let root = self.cur_board;
+        let seen: Vec<_> = layouts
+            .iter()
+            .filter_map(|l| l.read(&self.hw.ram))
+            .collect();
+        MoveGen::new_legal(&root).find(|m| seen.contains(&placement(&root.make_move_new(*m))))
+    }
+    // Tells the gui what the machine is up to, as far as its RAM gives it away.
+    fn report_progress(&mut self, started: Duration) {
+        let ram = &self.hw.ram;
+        let depth = self
+            .depth_slots
+            .as_deref()
+            .and_then(|s| read_learned(s, ram));
+        let score = self
+            .score_slots
+            .as_deref()
+            .and_then(|s| read_learned(s, ram));
+        let mut info = Vec::new();
+        if let Some(depth) = depth {
+            info.push(UciInfoAttribute::Depth(depth.clamp(0, u8::MAX as i32) as u8));
+        }
+        if let Some(cp) = score {
+            info.push(UciInfoAttribute::Score {
+                cp: Some(cp),
+                mate: None,
+                lower_bound: None,
+                upper_bound: None,
             });
         }
+        if let Some(m) = self.searched_move() {
+            info.push(UciInfoAttribute::CurrMove(m));
+        }
+        let elapsed = (self.virtual_now() - started) / self.throttle.policy().scale();
+        info.push(UciInfoAttribute::Time(
+            vampirc_uci::Duration::from_std(elapsed).unwrap_or(vampirc_uci::Duration::zero()),
+        ));
+        if let Some((best, ponder)) = self.ram_result(None) {
+            info.push(UciInfoAttribute::Pv(
+                std::iter::once(best).chain(ponder).collect(),
+            ));
+        }
+        out!("{}", UciMessage::Info(info));
     }
-}
-
-pub struct MM2 {
-    pub ram: [u8; 0x1000],
-    book: [u8; 0x4000],
-    rom: [u8; 0x8000],
-    cur_bitboard: [u8; 8],
-    pressed_keys: [[bool; 8]; 2],
-    outlatch: [bool; 8],
-    mux: usize,
-    display: [u8; 4],
-    last_display: [u8; 4],
-    display_pos: i8,
-    board_leds: [u8; 8],
-    board_leds_big: [u8; 64],
-    irq_done: bool,
-    led_square: Square,
-}
-
-impl MM2 {
-    pub fn new() -> MM2 {
-        // initialize RAM with all 0xFFs
-        let ram = [0xFF; 0x1000];
-        // initialize empty ROMs
-        let mut book = [0x00; 0x4000];
-        let mut rom = [0x00; 0x8000];
-        // Read book
-        read_file_into_slice("./hg240.rom", &mut book);
-        // read ROM
-        read_file_into_slice("./MM2.rom", &mut rom);
-        MM2 {
-            ram,
-            book,
-            rom,
-            cur_bitboard: [0; 8],
-            pressed_keys: [[false; 8]; 2],
-            mux: 0,
-            outlatch: [false; 8],
-            display: [0; 4],
-            last_display: [0; 4],
-            display_pos: 3,
-            board_leds: [0; 8],
-            board_leds_big: [0; 64],
-            irq_done: true,
-            led_square: Square::A1,
+    // The machine already thinks once the opponent's move is in, too late for
+    // a new level. So that move is taken back, the level selected and the move
+    // made again. That needs a snapshot from right before the last move, else
+    // the level is selected as is, which a position entered or replayed takes.
+    fn select_before_last_move(&mut self, select: impl FnOnce(&mut Self)) {
+        let before = match (&self.before_last_move, self.game_movs.last()) {
+            (Some(state), Some(&last))
+                if state.cur_board.legal(last)
+                    && state.cur_board.make_move_new(last) == self.cur_board =>
+            {
+                Some((state.clone(), last))
+            }
+            _ => None,
+        };
+        match before {
+            Some((state, last)) if self.load_state(&state).is_ok() => {
+                select(self);
+                self.play_move(last);
+            }
+            _ => select(self),
         }
     }
+    // levels count from 1 like the difficulty
+    fn set_level(&mut self, level: u8) {
+        self.press_key(MM2Button::LEV);
+        self.press_key(LEVEL_KEYS[level as usize - 1]);
+        self.press_key(MM2Button::ENT);
+    }
+    fn set_default_pos(&mut self) {
+        self.before_last_move = None;
+        self.warm_start(START_BITBOARD, true);
+        self.cur_board = Board::default();
+    }
+    fn make_half_move(&mut self, sq: chess::Square) {
+        let rank = sq.get_rank().to_index();
+        self.hw.cur_bitboard[rank] ^= 1 << (sq.get_file().to_index());
+        if !self.wait_scans(INPUT_SCANS, INPUT_TIMEOUT, |sys| sys.board_polls[rank]) {
+            out!("info Debug square {sq} was not scanned in time");
+        }
+        self.wait_irqs(SETTLE_IRQS);
+    }
 }
 
-impl System for MM2 {
-    fn read(&mut self, _cpu: &mut W65C02S, addr: u16) -> u8 {
-        match addr {
-            0..=0xfff => self.ram[addr as usize],
-            0x1800..=0x1807 => {
-                if self.pressed_keys[self.outlatch[7] as usize][(addr & 0xf) as usize] {
-                    0x7f
-                } else {
-                    0xff
-                }
+impl MephistoEmu for ModularEmu {
+    fn name(&self) -> String {
+        self.module.full_name()
+    }
+    // emulated time since power on
+    fn virtual_now(&self) -> Duration {
+        self.timing.cycles_to_duration(self.hw.cycles)
+    }
+    fn init(&mut self) {
+        out!("info Debug booting {}", self.module.name);
+        self.cpu.reset();
+        self.next_irq = self.hw.cycles + self.timing.irq_period();
+        self.hw.display_pos = 3;
+        self.hw.board_leds_big.copy_from_slice(&[0_u8; 64]);
+        self.wait_1sec();
+        self.wait_1sec();
+    }
+    // the crystal the module was built with
+    fn default_crystal_hz(&self) -> u64 {
+        self.module.timing.crystal_hz
+    }
+    fn set_crystal_hz(&mut self, crystal_hz: u64) {
+        self.timing.crystal_hz = crystal_hz;
+        self.throttle.reset();
+        out!(
+            "info Debug running at {}Hz, timer irq at {:.1}Hz",
+            crystal_hz,
+            self.timing.irq_hz()
+        );
+    }
+    fn set_clock_policy(&mut self, policy: ClockPolicy) {
+        self.throttle.set_policy(policy);
+        out!("info Debug clock policy: {:?}", policy);
+    }
+    fn save_state(&self) -> MachineState {
+        MachineState {
+            module: self.module.name.to_string(),
+            program_crc32: self.module.program.crc32,
+            cpu: self.cpu,
+            ram: self.hw.ram.to_vec(),
+            cur_bitboard: self.hw.cur_bitboard,
+            pressed_keys: self.hw.pressed_keys,
+            outlatch: self.hw.outlatch,
+            mux: self.hw.mux,
+            display: self.hw.display,
+            last_display: self.hw.last_display,
+            display_pos: self.hw.display_pos,
+            board_leds: self.hw.board_leds,
+            board_leds_big: self.hw.board_leds_big,
+            irq_done: self.hw.irq_done,
+            led_square: self.hw.led_square,
+            bank: self.hw.bank,
+            cycles: self.hw.cycles,
+            next_irq: self.next_irq,
+            cur_board: self.cur_board,
+            difficulty: self.difficulty,
+        }
+    }
+    fn load_state(&mut self, state: &MachineState) -> Result<(), Error> {
+        if state.module != self.module.name
+            || state.program_crc32 != self.module.program.crc32
+            || state.ram.len() != self.hw.ram.len()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Save state is for a different machine: {} with program {:08x}!",
+                    state.module, state.program_crc32
+                ),
+            ));
+        }
+        self.cpu = state.cpu;
+        self.cpu.set_irq(!state.irq_done);
+        self.hw.ram.copy_from_slice(&state.ram);
+        self.hw.cur_bitboard = state.cur_bitboard;
+        self.hw.pressed_keys = state.pressed_keys;
+        self.hw.outlatch = state.outlatch;
+        self.hw.mux = state.mux;
+        self.hw.display = state.display;
+        self.hw.last_display = state.last_display;
+        self.hw.display_pos = state.display_pos;
+        self.hw.board_leds = state.board_leds;
+        self.hw.board_leds_big = state.board_leds_big;
+        self.hw.irq_done = state.irq_done;
+        self.hw.led_square = state.led_square;
+        self.hw.bank = state.bank;
+        // emulated time only goes forward, so the snapshot is moved to now and
+        // just keeps where it was between two timer interrupts
+        self.next_irq = self.hw.cycles + state.next_irq.saturating_sub(state.cycles);
+        self.cur_board = state.cur_board;
+        self.difficulty = state.difficulty;
+        self.throttle.reset();
+        Ok(())
+    }
+    // A state the user saved. The game starts over at its position, what we
+    // knew about the old game doesn't belong to it.
+    fn load_game(&mut self, state: &MachineState) -> Result<(), Error> {
+        self.load_state(state)?;
+        self.game_start = self.cur_board.to_string();
+        self.game_movs.clear();
+        self.before_last_move = None;
+        Ok(())
+    }
+    fn set_difficulty(&mut self, new_difficulty: Option<u8>) -> Result<(), Error> {
+        if let Some(diff) = new_difficulty {
+            if !(1..=10).contains(&diff) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Difficulty can only be from 1 to 10!",
+                ));
             }
-            0x2000 => self.cur_bitboard[self.mux],
-            0x4000..=0x7fff => self.book[(addr - 0x4000) as usize],
-            0x8000.. => self.rom[(addr - 0x8000) as usize],
-            _ => {
-                println!("info Debug Read unknown address {:04X}! returning FF", addr);
-                0xff as u8
+            self.difficulty = diff - 1;
+        }
+        self.set_level(self.difficulty + 1);
+        Ok(())
+    }
+    // Plays at the strongest level of ELO_LEVELS that doesn't exceed elo, or
+    // the weakest. None only lifts the movetime cap, the level stays.
+    fn limit_strength(&mut self, elo: Option<u16>) -> Result<(), Error> {
+        let Some(elo) = elo else {
+            self.move_cap = None;
+            return Ok(());
+        };
+        if !(MIN_ELO..=MAX_ELO).contains(&elo) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Elo can only be from {MIN_ELO} to {MAX_ELO}!"),
+            ));
+        }
+        let (_, level, cap) = ELO_LEVELS
+            .iter()
+            .rev()
+            .find(|(e, _, _)| *e <= elo)
+            .unwrap_or(&ELO_LEVELS[0]);
+        self.move_cap = cap.map(Duration::from_millis);
+        self.set_difficulty(Some(*level))
+    }
+    fn set_position(&mut self, startpos: bool, fen: Option<UciFen>, movs: Vec<ChessMove>) {
+        let start = match (startpos, fen) {
+            (false, Some(fen)) => fen.as_str().to_string(),
+            _ => "startpos".to_string(),
+        };
+        self.game_start = start.clone();
+        self.game_movs = movs.clone();
+        if start == "startpos" && movs.len() <= 1 {
+            self.set_default_pos();
+            if let Some(mov) = movs.first() {
+                self.before_last_move = Some(self.save_state());
+                self.play_move(*mov);
             }
+        } else if !self.continue_game(&start, &movs) && !self.take_back(&start, &movs) {
+            self.replay(&start, &movs);
         }
+        self.check_sync();
     }
-    fn write(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
-        match addr {
-            0..=0xfff => self.ram[addr as usize] = value,
-            0x1000..=0x1007 => self.outlatch[(addr & 0xf) as usize] = (value & 0x80) > 0,
-            0x2800 => {
-                cpu.set_irq(false);
-                self.irq_done = true;
-                self.display[self.display_pos as usize] =
-                    if self.outlatch[7] { value } else { !value };
-                self.display_pos -= 1;
-                if self.display_pos < 0 {
-                    self.display_pos = 3;
-                    if self
-                        .display
-                        .iter()
-                        .zip(self.last_display.iter())
-                        .any(|(a, b)| a != b)
-                    {
-                        self.last_display.copy_from_slice(self.display.as_slice());
-                        println!(
-                            "{}",
-                            UciMessage::Info(vec![vampirc_uci::UciInfoAttribute::Any(
-                                "Display".to_string(),
-                                format!(
-                                    "{:?} {:?}",
-                                    self.display
-                                        .iter()
-                                        .map(|a| format!(
-                                            "{}{}",
-                                            LCD_MAP[*a as usize],
-                                            if *a & 0x80 == 0 { "." } else { "" }
-                                        ))
-                                        .collect::<String>(),
-                                    self.display.map(|a| format!("{a:08b}"))
-                                ),
-                            )])
-                        );
-                    }
-                }
+    // the start and moves of the last position command
+    fn game(&self) -> (String, Vec<ChessMove>) {
+        (self.game_start.clone(), self.game_movs.clone())
+    }
+    fn set_fen(&mut self, fen: &str) {
+        if fen == "startpos" {
+            return self.set_default_pos();
+        }
+        let board = match Board::from_str(fen) {
+            Ok(b) => b,
+            Err(e) => {
+                out!("info Debug invalid fen: {fen}, Error: {e}\ninfo Debug using default Board!");
+                Board::default()
             }
-            0x3000 => {
-                self.board_leds.copy_from_slice([0 as u8; 8].as_slice());
-                self.board_leds[self.mux] = value;
-                for i in 0..64 {
-                    if self.board_leds_big[i] > 0 {
-                        self.board_leds_big[i] -= 1;
-                    }
-                }
-                if value > 0 {
-                    let idx = self.mux * 8 + value.trailing_zeros() as usize;
-                    self.led_square = unsafe { Square::new(idx as u8) };
-                    self.board_leds_big[idx] = 0xff;
+        };
+        out!("info Debug cur board: {}", board);
+        match before_double_push(&board) {
+            Some((before, push)) => {
+                // set up the position before the double step and play it, so
+                // the firmware knows en passant is possible
+                self.set_board(&before);
+                self.force_moves(vec![push]);
+            }
+            None => self.set_board(&board),
+        }
+        self.cur_board = board;
+    }
+    fn force_moves(&mut self, movs: Vec<ChessMove>) {
+        self.press_key(MM2Button::LEV);
+        self.press_key(MM2Button::MEM);
+        self.press_key(MM2Button::ENT);
+        for mov in movs {
+            self.play_move(mov);
+        }
+        self.last_move_forced = true;
+    }
+    fn play_move(&mut self, mov: ChessMove) {
+        if !self.cur_board.legal(mov) {
+            panic!(
+                "info Debug Trying invalid move({})! cur_board: {}",
+                mov, self.cur_board
+            )
+        }
+        // remove piece at dest before making move
+        if self.cur_board.piece_on(mov.get_dest()).is_some() {
+            self.make_half_move(mov.get_dest());
+        }
+        // remove en passant piece
+        if let Some(passant) = self.cur_board.en_passant() {
+            self.cur_board = self.cur_board.make_move_new(mov);
+            if self.cur_board.piece_on(passant).is_none() {
+                self.make_half_move(passant);
+            }
+        } else {
+            self.cur_board = self.cur_board.make_move_new(mov);
+        }
+        self.make_half_move(mov.get_source());
+        self.make_half_move(mov.get_dest());
+        self.tone_count = 0;
+        // check casteling
+        if mov.get_source().get_file() == File::E
+            && self.cur_board.piece_on(mov.get_dest()).unwrap() == Piece::King
+            && (mov.get_dest().get_file() == File::G || mov.get_dest().get_file() == File::C)
+        {
+            let rank = mov.get_source().get_rank();
+            let sec_mov = if mov.get_dest().get_file() == chess::File::G {
+                ChessMove::new(
+                    Square::make_square(rank, File::H),
+                    Square::make_square(rank, File::F),
+                    None,
+                )
+            } else {
+                ChessMove::new(
+                    Square::make_square(rank, File::A),
+                    Square::make_square(rank, File::D),
+                    None,
+                )
+            };
+            self.make_half_move(sec_mov.get_source());
+            self.make_half_move(sec_mov.get_dest());
+        }
+        if mov.get_promotion().is_some() {
+            let prom = mov.get_promotion().unwrap();
+            self.press_key(PIECE_BUTTONS[prom as usize])
+        }
+    }
+    fn gen_move(
+        &mut self,
+        rec: &Receiver<UciMessage>,
+        time_control: Option<UciTimeControl>,
+    ) -> Option<UciMessage> {
+        self.pondering = matches!(time_control, Some(UciTimeControl::Ponder));
+        self.analysing = matches!(time_control, Some(UciTimeControl::Infinite));
+        if self.analysing {
+            self.select_before_last_move(|emu| emu.set_level(emu.module.analysis_level));
+        }
+        let infinite = self.analysing;
+        let end_time = match self.move_cap {
+            Some(cap) if !self.pondering && !self.analysing => {
+                let capped = self.virtual_now() + cap * self.throttle.policy().scale();
+                Some(
+                    self.deadline(time_control)
+                        .map_or(capped, |et| et.min(capped)),
+                )
+            }
+            _ => self.deadline(time_control),
+        };
+        let result = self.search(rec, end_time);
+        // the gui may only have the move once the ponder or analysis is over
+        while self.pondering || self.analysing {
+            match rec.recv() {
+                Ok(UciMessage::Go { .. }) if self.pondering => self.pondering = false,
+                Ok(UciMessage::Stop) => {
+                    self.pondering = false;
+                    self.analysing = false;
                 }
+                Ok(UciMessage::IsReady) => out!("{}", UciMessage::ReadyOk),
+                Ok(UciMessage::Quit) => std::process::exit(0),
+                Ok(message) => out!(
+                    "info Debug ignoring unknown UCI message during search: {}",
+                    message
+                ),
+                Err(_) => panic!("Stdin disconnected!"),
             }
-            0x3800 => self.mux = (!value).trailing_zeros() as usize,
-            _ => println!("info Debug Ignoring write of {value} to {addr}!"),
         }
+        if infinite {
+            // back to the level we play on
+            self.set_difficulty(None).unwrap();
+        }
+        result
+    }
+    fn solve_mate(
+        &mut self,
+        rec: &Receiver<UciMessage>,
+        moves: u8,
+        time_control: Option<UciTimeControl>,
+    ) -> Option<UciMessage> {
+        if !(1..=MAX_MATE_MOVES).contains(&moves) {
+            out!("info Debug can only look for mates in 1 to {MAX_MATE_MOVES} moves");
+        }
+        let moves = moves.clamp(1, MAX_MATE_MOVES);
+        // the mate level takes the number of moves right after it
+        self.select_before_last_move(|emu| {
+            emu.press_key(MM2Button::LEV);
+            emu.press_key(LEVEL_KEYS[emu.module.mate_level as usize - 1]);
+            emu.press_key(LEVEL_KEYS[moves as usize - 1]);
+            emu.press_key(MM2Button::ENT);
+        });
+        self.mate_found = None;
+        // the strength limit is for games, a mate search gets all its time
+        let move_cap = self.move_cap.take();
+        let result = self.gen_move(rec, time_control);
+        self.move_cap = move_cap;
+        if let (Some(UciMessage::BestMove { best_move, ponder }), Some(mate)) =
+            (&result, self.mate_found)
+        {
+            out!(
+                "{}",
+                UciMessage::Info(vec![
+                    UciInfoAttribute::Score {
+                        cp: None,
+                        mate: Some(mate),
+                        lower_bound: None,
+                        upper_bound: None
+                    },
+                    UciInfoAttribute::Pv(std::iter::once(*best_move).chain(*ponder).collect()),
+                ])
+            );
+        }
+        // back to the level we play on
+        self.set_difficulty(None).unwrap();
+        result
     }
 }
@@ -0,0 +1,68 @@
+use std::{
+    fs::File,
+    io::{Error, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+// turns a line for stdout into what the front end wants to show, if anything
+type Filter = Box<dyn Fn(&str) -> Option<String> + Send>;
+
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+static ECHO: AtomicBool = AtomicBool::new(true);
+static FILTER: Mutex<Option<Filter>> = Mutex::new(None);
+
+// Everything the engine says goes through here, so it can be kept in a log
+// file and kept off the terminal in the modes that print their own results.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::log::line(&format!($($arg)*))
+    };
+}
+
+pub fn open(path: &str) -> Result<(), Error> {
+    *LOG.lock().unwrap() = Some(File::create(path)?);
+    Ok(())
+}
+
+// whether engine output is printed to stdout
+pub fn set_echo(echo: bool) {
+    ECHO.store(echo, Ordering::Relaxed);
+}
+
+pub fn set_filter(filter: impl Fn(&str) -> Option<String> + Send + 'static) {
+    *FILTER.lock().unwrap() = Some(Box::new(filter));
+}
+
+pub fn line(s: &str) {
+    if ECHO.load(Ordering::Relaxed) {
+        let shown = match FILTER.lock().unwrap().as_ref() {
+            Some(filter) => filter(s),
+            None => Some(s.to_string()),
+        };
+        if let Some(shown) = shown {
+            println!("{shown}");
+        }
+    }
+    write(s);
+}
+
+// a line only meant for the front end, so filters don't touch it
+pub fn reply(s: &str) {
+    println!("{s}");
+    write(s);
+}
+
+// a line we received, only goes to the log
+pub fn incoming(s: &str) {
+    write(&format!("> {}", s.trim_end()));
+}
+
+fn write(s: &str) {
+    if let Some(f) = LOG.lock().unwrap().as_mut() {
+        // losing a log line is no reason to stop playing
+        let _ = writeln!(f, "{s}");
+    }
+}
@@ -1,56 +1,49 @@
+#[macro_use]
+mod log;
+mod cli;
 mod emu;
+mod modes;
+mod modular;
+mod ram;
+mod rom;
+mod savestate;
+mod timing;
 mod uci;
-mod utils;
+mod xboard;
 
-use emu::{MM2Emu, MephistoEmu};
-use std::{str::FromStr, sync::mpsc::TryRecvError, thread, time::Duration};
-use uci::{print_intro, spawn_stdin_channel};
-use vampirc_uci::UciMessage;
+use cli::{Mode, USAGE};
+use rom::RomLoader;
+use std::{env, process};
 
 pub fn main() {
-    let stdin_channel = spawn_stdin_channel();
-    let mut emu = MM2Emu::new();
-    let mut inited = false;
-    let mut set_diff = 1;
-    loop {
-        match stdin_channel.try_recv() {
-            Ok(message) => match message {
-                UciMessage::Uci => print_intro(),
-                UciMessage::IsReady => {
-                    if !inited {
-                        emu.init();
-                        emu.set_difficulty(Some(set_diff)).unwrap();
-                        inited = true;
-                    }
-                    println!("{}", UciMessage::ReadyOk);
-                }
-                UciMessage::SetOption { name, value } => match name.as_str() {
-                    "Difficulty" => set_diff = u8::from_str(value.unwrap().as_str()).unwrap(),
-                    "Debug" => {}
-                    _ => println!("info Debug unknown option: {name}, {}", value.unwrap()),
-                },
-                UciMessage::Position {
-                    startpos,
-                    fen,
-                    moves,
-                } => emu.set_position(startpos, fen, moves),
-                UciMessage::Go {
-                    time_control,
-                    search_control: _search_control,
-                } => {
-                    if let Some(mov) = emu.gen_move(&stdin_channel, time_control) {
-                        println!("{}", mov);
-                    }
-                }
-                UciMessage::UciNewGame => {}
-                UciMessage::Quit => return,
-                _ => println!("info Debug unhandled message: {}", message),
-            },
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => {
-                panic!("Stdin disconnected!")
-            }
+    let args: Vec<String> = env::args().collect();
+    let opts = match cli::parse(&args) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print!("{USAGE}");
+            return;
         }
-        thread::sleep(Duration::from_millis(500));
+        Err(e) => {
+            eprint!("{e}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Some(path) = &opts.log {
+        if let Err(e) = log::open(path) {
+            eprintln!("Could not open log file {path}: {e}");
+            process::exit(1);
+        }
+    }
+    let mut roms = RomLoader::new(&opts.rom_paths);
+    for (name, path) in &opts.roms {
+        roms = roms.with_file(name, path.clone());
+    }
+    match opts.mode {
+        Mode::Uci => uci::run(&opts, &roms),
+        Mode::Xboard => xboard::run(&opts, &roms),
+        Mode::Interactive => modes::interactive(&opts, &roms),
+        Mode::Bench => modes::bench(&opts, &roms),
+        Mode::Epd => modes::epd(&opts, &roms),
+        Mode::Selfplay => modes::selfplay(&opts, &roms),
     }
 }
@@ -0,0 +1,390 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    process,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Instant,
+};
+
+use chess::{Board, BoardStatus, ChessMove, Color, Game, GameResult, Square, ALL_FILES, ALL_RANKS};
+use vampirc_uci::{UciFen, UciMessage, UciTimeControl};
+
+use crate::{
+    cli::Options,
+    emu::{new_emu, MephistoEmu},
+    log,
+    rom::RomLoader,
+    uci::clock_policy,
+};
+
+// the perft test positions, a mix of opening, middle game and endgame
+const BENCH_POSITIONS: [&str; 4] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+];
+
+// games that go on longer than this are called a draw
+const MAX_PLIES: usize = 400;
+
+// The machine and the stop channel it polls while thinking. Nothing is ever
+// sent, the sender just has to stay alive.
+struct Engine {
+    emu: Box<dyn MephistoEmu>,
+    _stop: Sender<UciMessage>,
+    rec: Receiver<UciMessage>,
+}
+
+impl Engine {
+    fn start(opts: &Options, roms: &RomLoader, module: &str) -> Engine {
+        let mut emu = match new_emu(module, &opts.library, roms) {
+            Ok(emu) => emu,
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        };
+        emu.set_clock_policy(clock_policy(&opts.clock_mode, opts.speed));
+        emu.init();
+        emu.set_difficulty(Some(opts.difficulty)).unwrap();
+        let (stop, rec) = mpsc::channel();
+        Engine {
+            emu,
+            _stop: stop,
+            rec,
+        }
+    }
+    fn think(&mut self, start: &Board, moves: &[ChessMove], movetime: u64) -> Option<ChessMove> {
+        let startpos = *start == Board::default();
+        let fen = (!startpos).then(|| UciFen(start.to_string()));
+        self.emu.set_position(startpos, fen, moves.to_vec());
+        let time = UciTimeControl::MoveTime(vampirc_uci::Duration::milliseconds(movetime as i64));
+        match self.emu.gen_move(&self.rec, Some(time)) {
+            Some(UciMessage::BestMove { best_move, .. }) => Some(best_move),
+            _ => None,
+        }
+    }
+}
+
+// Searches a few fixed positions and reports how fast the emulation runs.
+pub fn bench(opts: &Options, roms: &RomLoader) {
+    log::set_echo(false);
+    let mut engine = Engine::start(opts, roms, &opts.module);
+    println!("{}, {}ms per position", engine.emu.name(), opts.movetime);
+    let (start_wall, start_virtual) = (Instant::now(), engine.emu.virtual_now());
+    for fen in BENCH_POSITIONS {
+        let board = Board::from_str(fen).unwrap();
+        let (wall, virt) = (Instant::now(), engine.emu.virtual_now());
+        let best = engine.think(&board, &[], opts.movetime);
+        let (wall, virt) = (wall.elapsed(), engine.emu.virtual_now() - virt);
+        println!(
+            "{fen}: {} in {:.2}s emulated, {:.2}s wall",
+            best.map_or("none".to_string(), |m| m.to_string()),
+            virt.as_secs_f64(),
+            wall.as_secs_f64()
+        );
+    }
+    let (wall, virt) = (
+        start_wall.elapsed(),
+        engine.emu.virtual_now() - start_virtual,
+    );
+    println!(
+        "total {:.2}s emulated in {:.2}s wall, {:.1}x real time",
+        virt.as_secs_f64(),
+        wall.as_secs_f64(),
+        virt.as_secs_f64() / wall.as_secs_f64()
+    );
+}
+
+// One EPD line: the position and the moves it wants (bm) or doesn't (am).
+struct EpdEntry {
+    board: Board,
+    id: String,
+    best: Vec<ChessMove>,
+    avoid: Vec<ChessMove>,
+}
+
+fn parse_epd(line: &str) -> Option<EpdEntry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let board = Board::from_str(&format!("{} 0 1", fields[..4].join(" "))).ok()?;
+    let mut entry = EpdEntry {
+        board,
+        id: String::new(),
+        best: Vec::new(),
+        avoid: Vec::new(),
+    };
+    let ops = fields[4..].join(" ");
+    for op in ops.split(';').map(str::trim) {
+        let (opcode, operands) = op.split_once(' ').unwrap_or((op, ""));
+        let moves = || {
+            operands
+                .split_whitespace()
+                .map(|san| ChessMove::from_san(&board, san).ok())
+                .collect::<Option<Vec<_>>>()
+        };
+        match opcode {
+            // a suite we can't read right would be scored wrong
+            "bm" => entry.best = moves()?,
+            "am" => entry.avoid = moves()?,
+            "id" => entry.id = operands.trim_matches('"').to_string(),
+            _ => {}
+        }
+    }
+    Some(entry)
+}
+
+// Runs a test suite and counts the positions where the machine finds bm
+// and stays away from am.
+pub fn epd(opts: &Options, roms: &RomLoader) {
+    log::set_echo(false);
+    let path = opts.epd.as_ref().unwrap();
+    let suite = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not read {}: {e}", path.display());
+            process::exit(1);
+        }
+    };
+    let mut engine = Engine::start(opts, roms, &opts.module);
+    let (mut solved, mut total, mut skipped) = (0, 0, 0);
+    for line in suite.lines().filter(|l| !l.trim().is_empty()) {
+        let Some(entry) = parse_epd(line) else {
+            println!("skipping unreadable line: {line}");
+            skipped += 1;
+            continue;
+        };
+        total += 1;
+        let found = engine.think(&entry.board, &[], opts.movetime);
+        let ok = found.is_some_and(|m| {
+            (entry.best.is_empty() || entry.best.contains(&m)) && !entry.avoid.contains(&m)
+        });
+        if ok {
+            solved += 1;
+        }
+        println!(
+            "{} {}: {}",
+            if ok { "ok  " } else { "FAIL" },
+            if entry.id.is_empty() { line } else { &entry.id },
+            found.map_or("none".to_string(), |m| m.to_string())
+        );
+    }
+    println!("solved {solved} of {total}, skipped {skipped}");
+}
+
+// Plays games of the module against itself or another module, swapping
+// colours after every game.
+pub fn selfplay(opts: &Options, roms: &RomLoader) {
+    log::set_echo(false);
+    let opponent = opts.opponent.as_deref().unwrap_or(&opts.module);
+    let mut engines = [
+        Engine::start(opts, roms, &opts.module),
+        Engine::start(opts, roms, opponent),
+    ];
+    let mut points = [0.0, 0.0];
+    for round in 0..opts.games as usize {
+        // index of the engine playing white
+        let white = round % 2;
+        let mut game = Game::new();
+        let mut moves = Vec::new();
+        let mut adjudicated = false;
+        while game.result().is_none() {
+            if game.can_declare_draw() || moves.len() >= MAX_PLIES {
+                adjudicated = true;
+                break;
+            }
+            let side = if game.side_to_move() == Color::White {
+                white
+            } else {
+                1 - white
+            };
+            match engines[side].think(&Board::default(), &moves, opts.movetime) {
+                Some(m) if game.current_position().legal(m) => {
+                    game.make_move(m);
+                    moves.push(m);
+                }
+                _ => {
+                    game.resign(game.side_to_move());
+                }
+            }
+        }
+        let result = if adjudicated {
+            GameResult::DrawDeclared
+        } else {
+            game.result().unwrap()
+        };
+        let white_score = match result {
+            GameResult::WhiteCheckmates | GameResult::BlackResigns => 1.0,
+            GameResult::BlackCheckmates | GameResult::WhiteResigns => 0.0,
+            _ => 0.5,
+        };
+        points[white] += white_score;
+        points[1 - white] += 1.0 - white_score;
+        println!(
+            "game {}: {} - {}: {}, {:?}",
+            round + 1,
+            engines[white].emu.name(),
+            engines[1 - white].emu.name(),
+            moves
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            result
+        );
+    }
+    println!(
+        "{} {} - {} {}",
+        engines[0].emu.name(),
+        points[0],
+        points[1],
+        engines[1].emu.name()
+    );
+}
+
+fn print_board(board: &Board) {
+    for rank in ALL_RANKS.iter().rev() {
+        let row: String = ALL_FILES
+            .iter()
+            .map(|file| {
+                let sq = Square::make_square(*rank, *file);
+                match (board.piece_on(sq), board.color_on(sq)) {
+                    (Some(piece), Some(color)) => piece.to_string(color).chars().next().unwrap(),
+                    _ => '.',
+                }
+            })
+            .collect();
+        println!("{} {row}", rank.to_index() + 1);
+    }
+    println!("  abcdefgh");
+    match board.side_to_move() {
+        Color::White => println!("white to move"),
+        Color::Black => println!("black to move"),
+    }
+}
+
+// Play against the machine on the terminal. Moves are typed in coordinate
+// or algebraic notation, the machine answers right away.
+pub fn interactive(opts: &Options, roms: &RomLoader) {
+    log::set_echo(false);
+    let mut engine = Engine::start(opts, roms, &opts.module);
+    println!(
+        "{}, type a move, go, new, fen <fen>, board or quit",
+        engine.emu.name()
+    );
+    let mut start = Board::default();
+    let mut moves: Vec<ChessMove> = Vec::new();
+    let mut board = start;
+    print_board(&board);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        let line = line.trim();
+        let engine_to_move = match line {
+            "" => false,
+            "quit" => return,
+            "board" => {
+                print_board(&board);
+                false
+            }
+            "new" => {
+                start = Board::default();
+                moves.clear();
+                board = start;
+                print_board(&board);
+                false
+            }
+            "go" => true,
+            _ if line.starts_with("fen ") => match Board::from_str(&line[4..]) {
+                Ok(b) => {
+                    start = b;
+                    moves.clear();
+                    board = start;
+                    print_board(&board);
+                    false
+                }
+                Err(_) => {
+                    println!("not a valid fen");
+                    false
+                }
+            },
+            _ => {
+                let m = ChessMove::from_str(line)
+                    .ok()
+                    .filter(|m| board.legal(*m))
+                    .or_else(|| ChessMove::from_san(&board, line).ok());
+                match m {
+                    Some(m) => {
+                        moves.push(m);
+                        board = board.make_move_new(m);
+                        true
+                    }
+                    None => {
+                        println!("illegal move {line}");
+                        false
+                    }
+                }
+            }
+        };
+        if engine_to_move && board.status() == BoardStatus::Ongoing {
+            match engine.think(&start, &moves, opts.movetime) {
+                Some(m) if board.legal(m) => {
+                    println!("{} plays {m}", engine.emu.name());
+                    moves.push(m);
+                    board = board.make_move_new(m);
+                }
+                _ => println!("{} has no move", engine.emu.name()),
+            }
+            print_board(&board);
+        }
+        match board.status() {
+            BoardStatus::Checkmate => println!("checkmate"),
+            BoardStatus::Stalemate => println!("stalemate"),
+            BoardStatus::Ongoing => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_epd_operations() {
+        let entry = parse_epd(
+            r#"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - bm e4 d4; am f3; id "start.1";"#,
+        )
+        .unwrap();
+        assert_eq!(entry.board, Board::default());
+        assert_eq!(entry.id, "start.1");
+        assert_eq!(
+            entry.best,
+            [
+                ChessMove::new(Square::E2, Square::E4, None),
+                ChessMove::new(Square::D2, Square::D4, None)
+            ]
+        );
+        assert_eq!(entry.avoid, [ChessMove::new(Square::F2, Square::F3, None)]);
+    }
+
+    #[test]
+    fn skips_unreadable_epd_lines() {
+        assert!(parse_epd("not an epd").is_none());
+        assert!(parse_epd("not an epd line").is_none());
+        // a bm or am move that isn't legal drops the whole line
+        assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 b - - bm Qxh7;").is_none());
+        assert!(parse_epd("4k3/8/8/8/8/8/8/4K3 b - - bm Kd7; am Ke1;").is_none());
+        let entry = parse_epd("4k3/8/8/8/8/8/8/4K3 b - - am Kd7;").unwrap();
+        assert!(entry.best.is_empty());
+        assert!(entry.id.is_empty());
+    }
+}
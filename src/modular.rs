@@ -0,0 +1,599 @@
+use chess::{Piece, Square};
+use vampirc_uci::{UciInfoAttribute, UciMessage};
+use w65c02s::{System, W65C02S};
+
+use crate::{
+    rom::{check_program, load_rom, RomInfo, RomLoader},
+    timing::{
+        Timing, MM2_CRYSTAL_HZ, MM2_IRQ_DIVIDER, MM4_CRYSTAL_HZ, MM4_IRQ_DIVIDER, MM5_CRYSTAL_HZ,
+        MM5_IRQ_DIVIDER,
+    },
+};
+use std::io::{Error, ErrorKind};
+
+const fn calc_lcd_map() -> [char; 0x100] {
+    let mut res = ['☐'; 0x100];
+    let vals = [
+        (' ', 0b11111111),
+        ('-', 0b11110111),
+        ('a', 0b10100000),
+        ('b', 0b10000011),
+        ('c', 0b10100111),
+        ('d', 0b10100001),
+        ('e', 0b10000100),
+        ('f', 0b10001110),
+        ('g', 0b10010000),
+        ('h', 0b10001011),
+        ('i', 0b11101111),
+        ('j', 0b11110011),
+        ('k', 0b10001010),
+        ('l', 0b11001111),
+        ('m', 0b11101011),
+        ('n', 0b10101011),
+        ('o', 0b10100011),
+        ('p', 0b10001100),
+        ('q', 0b10011000),
+        ('r', 0b10101111),
+        ('s', 0b10010010),
+        ('t', 0b10000111),
+        ('u', 0b11100011),
+        ('v', 0b11100011),
+        ('w', 0b11101011),
+        ('x', 0b10001001),
+        ('y', 0b10010001),
+        ('z', 0b10100100),
+        ('A', 0b10001000),
+        ('B', 0b10000011),
+        ('C', 0b11000110),
+        ('D', 0b10100001),
+        ('E', 0b10000110),
+        ('F', 0b10001110),
+        ('G', 0b11000010),
+        ('H', 0b10001001),
+        ('I', 0b11001111),
+        ('J', 0b11100001),
+        ('K', 0b10001010),
+        ('L', 0b11000111),
+        ('M', 0b11101010),
+        ('N', 0b11001000),
+        ('O', 0b11000000),
+        ('P', 0b10001100),
+        ('Q', 0b10010100),
+        ('R', 0b11001100),
+        ('S', 0b10010010),
+        ('T', 0b11001110),
+        ('U', 0b11000001),
+        ('V', 0b11000001),
+        ('W', 0b11010101),
+        ('Y', 0b10010001),
+        ('Z', 0b10100100),
+        ('0', 0b11000000),
+        ('1', 0b11111001),
+        ('2', 0b10100100),
+        ('3', 0b10110000),
+        ('4', 0b10011001),
+        ('5', 0b10010010),
+        ('6', 0b10000010),
+        ('7', 0b11111000),
+        ('8', 0b10000000),
+        ('9', 0b10010000),
+        (']', 0b11110000),
+        ('=', 0b11110110),
+        ('K', 0b10000101),
+        ('-', 0b10111111),
+    ];
+    let mut i = 0;
+    while i < vals.len() {
+        let (c, j) = vals[i];
+        res[j as usize] = c;
+        res[(j & 0x7f) as usize] = c;
+        i += 1;
+    }
+    res
+}
+pub const LCD_MAP: [char; 0x100] = calc_lcd_map();
+const _LED_NAMES: [&str; 8] = [
+    "black_led",
+    "white_led",
+    "calc_led",
+    "mem_led",
+    "pos_led",
+    "play_led",
+    "play_tone",
+    "strobe_lcd",
+];
+
+// The parts every board of the Modular series shares: RAM, the sensor board
+// with its leds, the keypad, the LCD and the outlatch driving the status leds.
+// Only where they sit in the address space differs between the modules.
+pub struct Hardware {
+    pub ram: Vec<u8>,
+    pub cur_bitboard: [u8; 8],
+    pub pressed_keys: [[bool; 8]; 2],
+    pub outlatch: [bool; 8],
+    pub mux: usize,
+    pub display: [u8; 4],
+    pub last_display: [u8; 4],
+    pub display_pos: i8,
+    pub board_leds: [u8; 8],
+    pub board_leds_big: [u8; 64],
+    pub irq_done: bool,
+    pub led_square: Square,
+    // which part of a banked ROM shows through its window
+    pub bank: u8,
+    // the last value seen on the data bus
+    pub data_bus: u8,
+    // every bus access is one cpu cycle
+    pub cycles: u64,
+    // how often the firmware read each key and sensor row
+    pub key_polls: [[u64; 8]; 2],
+    pub board_polls: [u64; 8],
+}
+
+impl Hardware {
+    pub fn new(ram_size: usize) -> Hardware {
+        Hardware {
+            // initialize RAM with all 0xFFs
+            ram: vec![0xFF; ram_size],
+            cur_bitboard: [0; 8],
+            pressed_keys: [[false; 8]; 2],
+            mux: 0,
+            outlatch: [false; 8],
+            display: [0; 4],
+            last_display: [0; 4],
+            display_pos: 3,
+            board_leds: [0; 8],
+            board_leds_big: [0; 64],
+            irq_done: true,
+            led_square: Square::A1,
+            bank: 0,
+            data_bus: 0,
+            cycles: 0,
+            key_polls: [[0; 8]; 2],
+            board_polls: [0; 8],
+        }
+    }
+    pub fn read_keys(&mut self, row: usize) -> u8 {
+        self.key_polls[self.outlatch[7] as usize][row] += 1;
+        if self.pressed_keys[self.outlatch[7] as usize][row] {
+            0x7f
+        } else {
+            0xff
+        }
+    }
+    pub fn read_board(&mut self) -> u8 {
+        self.board_polls[self.mux] += 1;
+        self.cur_bitboard[self.mux]
+    }
+    pub fn write_outlatch(&mut self, bit: usize, value: u8) {
+        self.outlatch[bit] = (value & 0x80) > 0;
+    }
+    pub fn ack_irq(&mut self, cpu: &mut W65C02S) {
+        cpu.set_irq(false);
+        self.irq_done = true;
+    }
+    pub fn write_display(&mut self, value: u8) {
+        self.display[self.display_pos as usize] = if self.outlatch[7] { value } else { !value };
+        self.display_pos -= 1;
+        if self.display_pos < 0 {
+            self.display_pos = 3;
+            if self
+                .display
+                .iter()
+                .zip(self.last_display.iter())
+                .any(|(a, b)| a != b)
+            {
+                self.last_display.copy_from_slice(self.display.as_slice());
+                out!(
+                    "{}",
+                    UciMessage::Info(vec![UciInfoAttribute::Any(
+                        "Display".to_string(),
+                        format!(
+                            "{:?} {:?}",
+                            self.display
+                                .iter()
+                                .map(|a| format!(
+                                    "{}{}",
+                                    LCD_MAP[*a as usize],
+                                    if *a & 0x80 == 0 { "." } else { "" }
+                                ))
+                                .collect::<String>(),
+                            self.display.map(|a| format!("{a:08b}"))
+                        ),
+                    )])
+                );
+            }
+        }
+    }
+    pub fn write_leds(&mut self, value: u8) {
+        self.board_leds.copy_from_slice([0_u8; 8].as_slice());
+        self.board_leds[self.mux] = value;
+        for i in 0..64 {
+            if self.board_leds_big[i] > 0 {
+                self.board_leds_big[i] -= 1;
+            }
+        }
+        if value > 0 {
+            let idx = self.mux * 8 + value.trailing_zeros() as usize;
+            self.led_square = unsafe { Square::new(idx as u8) };
+            self.board_leds_big[idx] = 0xff;
+        }
+    }
+    pub fn write_mux(&mut self, value: u8) {
+        self.mux = (!value).trailing_zeros() as usize;
+    }
+    pub fn unmapped_read(&self, addr: u16) -> u8 {
+        out!("info Debug Read unknown address {:04X}! returning FF", addr);
+        0xff_u8
+    }
+    pub fn unmapped_write(&self, addr: u16, value: u8) {
+        out!("info Debug Ignoring write of {value} to {addr}!");
+    }
+}
+
+// What a range of the address space is wired to. Devices ignore the address
+// bits below their range, except the keypad and the outlatch which use the
+// lowest three to pick a row or bit.
+pub enum Region {
+    // the start of the range is the start of RAM
+    Ram,
+    Rom(Vec<u8>),
+    // a ROM bigger than its window, the bank latch picks the part that shows
+    BankedRom(Vec<u8>),
+    // nothing answers, the cpu reads whatever was last on the data bus
+    OpenBus,
+    Keys,
+    SensorBoard,
+    Outlatch,
+    Display,
+    Leds,
+    Mux,
+    BankLatch,
+}
+
+pub struct Mapping {
+    pub start: u16,
+    pub end: u16,
+    pub region: Region,
+}
+
+const UNMAPPED: u8 = 0xff;
+
+// The address space of a module.
+pub struct MemoryMap {
+    mappings: Vec<Mapping>,
+    // index into mappings for every address, so decoding is a single lookup
+    decode: Vec<u8>,
+}
+
+impl MemoryMap {
+    pub fn new(mappings: Vec<Mapping>) -> MemoryMap {
+        let mut decode = vec![UNMAPPED; 0x10000];
+        // the first mapping covering an address wins
+        for (i, m) in mappings.iter().enumerate().rev() {
+            decode[m.start as usize..=m.end as usize].fill(i as u8);
+        }
+        MemoryMap { mappings, decode }
+    }
+    fn find(&self, addr: u16) -> Option<&Mapping> {
+        self.mappings.get(self.decode[addr as usize] as usize)
+    }
+    pub fn read(&self, hw: &mut Hardware, addr: u16) -> u8 {
+        let Some(m) = self.find(addr) else {
+            return hw.unmapped_read(addr);
+        };
+        let offset = (addr - m.start) as usize;
+        match &m.region {
+            Region::Ram => hw.ram[offset],
+            Region::Rom(data) => data[offset],
+            Region::BankedRom(data) => {
+                let window = (m.end - m.start) as usize + 1;
+                data[(hw.bank as usize * window + offset) % data.len()]
+            }
+            Region::OpenBus => hw.data_bus,
+            Region::Keys => hw.read_keys(offset & 0x7),
+            Region::SensorBoard => hw.read_board(),
+            _ => hw.unmapped_read(addr),
+        }
+    }
+    // returns true if the write acknowledged the timer interrupt
+    pub fn write(&self, hw: &mut Hardware, addr: u16, value: u8) -> bool {
+        let Some(m) = self.find(addr) else {
+            hw.unmapped_write(addr, value);
+            return false;
+        };
+        let offset = (addr - m.start) as usize;
+        match &m.region {
+            Region::Ram => hw.ram[offset] = value,
+            Region::Outlatch => hw.write_outlatch(offset & 0x7, value),
+            Region::Display => {
+                hw.write_display(value);
+                return true;
+            }
+            Region::Leds => hw.write_leds(value),
+            Region::Mux => hw.write_mux(value),
+            Region::BankLatch => hw.bank = value,
+            _ => hw.unmapped_write(addr, value),
+        }
+        false
+    }
+}
+
+// The texts the firmware shows for the things we have to react to.
+#[derive(Clone, Copy)]
+pub struct Messages {
+    // followed by the number of moves
+    pub mate: &'static str,
+    // followed by the piece letter
+    pub promotion: &'static str,
+    pub promotion_pieces: [(char, Piece); 4],
+    pub play: &'static str,
+    // the firmware has no move to show
+    pub no_move: &'static str,
+}
+
+pub const MM2_MESSAGES: Messages = Messages {
+    mate: " N ",
+    promotion: "Pr",
+    promotion_pieces: [
+        ('D', Piece::Queen),
+        ('T', Piece::Rook),
+        ('5', Piece::Knight),
+        ('L', Piece::Bishop),
+    ],
+    play: "PLAY",
+    no_move: "NAT ",
+};
+
+// How the timer interrupt gets taken off the cpu again.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IrqAck {
+    // by the display update the firmware does in its interrupt handler
+    DisplayWrite,
+    // as soon as the cpu fetches the interrupt vector
+    Vector,
+}
+
+// A board of the Modular series. Everything that differs between the boards
+// is in here, the hardware they drive is the same.
+pub struct Module {
+    pub name: &'static str,
+    pub timing: Timing,
+    pub ram_size: usize,
+    pub irq_ack: IrqAck,
+    pub messages: Messages,
+    // the level that solves mate problems, counted like the difficulty
+    pub mate_level: u8,
+    // the level that thinks until it is told to move
+    pub analysis_level: u8,
+    pub map: MemoryMap,
+    pub program: RomInfo,
+}
+
+pub const MODULES: [&str; 3] = ["MM2", "MM4", "MM5"];
+// the most RAM any of them has
+pub const MAX_RAM_SIZE: usize = 0x2000;
+// Default is the library a module was sold with
+pub const LIBRARIES: [&str; 5] = ["Default", "HG240", "HG170", "HG550", "None"];
+
+// what shows in the library window
+fn library(roms: &RomLoader, name: &str) -> Result<Region, Error> {
+    Ok(match name {
+        "HG240" => Region::Rom(load_rom(roms, "hg240.rom", 0x4000)?.0),
+        "HG170" => Region::Rom(load_rom(roms, "hg170.rom", 0x4000)?.0),
+        "HG550" => Region::BankedRom(load_rom(roms, "hg550.rom", 0x10000)?.0),
+        "None" => Region::OpenBus,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown library {name}! Known are {}", LIBRARIES.join(", ")),
+            ))
+        }
+    })
+}
+
+// the program ROM, mapped from 0x8000 to the top
+fn program(roms: &RomLoader, name: &str) -> Result<(Region, RomInfo), Error> {
+    let (data, info) = load_rom(roms, name, 0x8000)?;
+    check_program(&data, 0x8000, &info)?;
+    Ok((Region::Rom(data), info))
+}
+
+impl Module {
+    pub fn by_name(name: &str, library_name: &str, roms: &RomLoader) -> Result<Module, Error> {
+        let library_name = match (name, library_name) {
+            ("MM5", "Default") => "HG550",
+            (_, "Default") => "HG240",
+            (_, l) => l,
+        };
+        match name {
+            "MM2" => Module::mm2(roms, library(roms, library_name)?),
+            "MM4" => Module::mm4(roms, library(roms, library_name)?),
+            "MM5" => Module::mm5(roms, library(roms, library_name)?),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown module {name}! Known are {}", MODULES.join(", ")),
+            )),
+        }
+    }
+    // what to call the machine, with the program version if we know it
+    pub fn full_name(&self) -> String {
+        match &self.program.known {
+            Some(known) => format!("Mephisto {}", known.name),
+            None => format!("Mephisto {}", self.name),
+        }
+    }
+    pub fn mm2(roms: &RomLoader, library: Region) -> Result<Module, Error> {
+        let (program, info) = program(roms, "MM2.rom")?;
+        Ok(Module {
+            name: "MM2",
+            timing: Timing::new(MM2_CRYSTAL_HZ, MM2_IRQ_DIVIDER),
+            ram_size: 0x1000,
+            irq_ack: IrqAck::DisplayWrite,
+            messages: MM2_MESSAGES,
+            mate_level: 9,
+            analysis_level: 10,
+            map: MemoryMap::new(vec![
+                map(0x0000, 0x0fff, Region::Ram),
+                map(0x1000, 0x1007, Region::Outlatch),
+                map(0x1800, 0x1807, Region::Keys),
+                map(0x2000, 0x2000, Region::SensorBoard),
+                map(0x2800, 0x2800, Region::Display),
+                map(0x3000, 0x3000, Region::Leds),
+                map(0x3800, 0x3800, Region::Mux),
+                map(0x4000, 0x7fff, library),
+                map(0x8000, 0xffff, program),
+            ]),
+            program: info,
+        })
+    }
+    // MM IV: twice the RAM, a faster crystal and the i/o moved up to make room
+    // for it. The library module plugs into the same slot as on the MM II.
+    pub fn mm4(roms: &RomLoader, library: Region) -> Result<Module, Error> {
+        let (program, info) = program(roms, "MM4.rom")?;
+        Ok(Module {
+            name: "MM4",
+            timing: Timing::new(MM4_CRYSTAL_HZ, MM4_IRQ_DIVIDER),
+            ram_size: 0x2000,
+            irq_ack: IrqAck::Vector,
+            // assumed to be the MM II's, neither was checked on an MM IV
+            messages: MM2_MESSAGES,
+            mate_level: 9,
+            analysis_level: 10,
+            map: MemoryMap::new(vec![
+                map(0x0000, 0x1fff, Region::Ram),
+                map(0x2000, 0x2007, Region::Outlatch),
+                map(0x2400, 0x2407, Region::Keys),
+                map(0x2800, 0x2800, Region::Mux),
+                map(0x2c00, 0x2c00, Region::Leds),
+                map(0x3000, 0x3000, Region::SensorBoard),
+                map(0x3400, 0x3400, Region::Display),
+                map(0x4000, 0x7fff, library),
+                map(0x8000, 0xffff, program),
+            ]),
+            program: info,
+        })
+    }
+    // MM V: the MM IV board with a 5MHz crystal. Its HG550 library is too big
+    // for the library window and is switched through it in 16K banks.
+    // A placeholder: the program is mapped like the MM IV's 32K one, and the
+    // bank latch address, the mate text and the levels are guesses that were
+    // never checked against a real MM V.
+    pub fn mm5(roms: &RomLoader, library: Region) -> Result<Module, Error> {
+        let (program, info) = program(roms, "MM5.rom")?;
+        Ok(Module {
+            name: "MM5",
+            timing: Timing::new(MM5_CRYSTAL_HZ, MM5_IRQ_DIVIDER),
+            ram_size: 0x2000,
+            irq_ack: IrqAck::Vector,
+            messages: Messages {
+                mate: "MA",
+                ..MM2_MESSAGES
+            },
+            mate_level: 9,
+            analysis_level: 10,
+            map: MemoryMap::new(vec![
+                map(0x0000, 0x1fff, Region::Ram),
+                map(0x2000, 0x2007, Region::Outlatch),
+                map(0x2400, 0x2407, Region::Keys),
+                map(0x2800, 0x2800, Region::Mux),
+                map(0x2c00, 0x2c00, Region::Leds),
+                map(0x3000, 0x3000, Region::SensorBoard),
+                map(0x3400, 0x3400, Region::Display),
+                map(0x3c00, 0x3c00, Region::BankLatch),
+                map(0x4000, 0x7fff, library),
+                map(0x8000, 0xffff, program),
+            ]),
+            program: info,
+        })
+    }
+}
+
+fn map(start: u16, end: u16, region: Region) -> Mapping {
+    Mapping { start, end, region }
+}
+
+// What the cpu sees: a module's memory map on top of the hardware.
+pub struct Bus<'a> {
+    pub hw: &'a mut Hardware,
+    pub module: &'a Module,
+}
+
+impl System for Bus<'_> {
+    fn read(&mut self, _cpu: &mut W65C02S, addr: u16) -> u8 {
+        self.hw.cycles += 1;
+        self.hw.data_bus = self.module.map.read(self.hw, addr);
+        self.hw.data_bus
+    }
+    fn write(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
+        self.hw.cycles += 1;
+        self.hw.data_bus = value;
+        if self.module.map.write(self.hw, addr, value)
+            && self.module.irq_ack == IrqAck::DisplayWrite
+        {
+            self.hw.ack_irq(cpu);
+        }
+    }
+    fn read_vector(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
+        if addr == w65c02s::IRQ_VECTOR && self.module.irq_ack == IrqAck::Vector {
+            self.hw.ack_irq(cpu);
+        }
+        self.read(cpu, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(mappings: Vec<(u16, u16, Region)>) -> MemoryMap {
+        MemoryMap::new(
+            mappings
+                .into_iter()
+                .map(|(start, end, region)| Mapping { start, end, region })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn first_mapping_wins_where_ranges_overlap() {
+        let map = map(vec![
+            (0x1000, 0x1fff, Region::Rom(vec![0xaa; 0x1000])),
+            (0x0000, 0x7fff, Region::Ram),
+        ]);
+        let mut hw = Hardware::new(0x8000);
+        hw.ram.fill(0x55);
+        assert_eq!(map.read(&mut hw, 0x1000), 0xaa);
+        assert_eq!(map.read(&mut hw, 0x1fff), 0xaa);
+        assert_eq!(map.read(&mut hw, 0x0fff), 0x55);
+        assert_eq!(map.read(&mut hw, 0x2000), 0x55);
+        // the ROM takes the write, the RAM under it keeps its value
+        map.write(&mut hw, 0x1234, 0x12);
+        assert_eq!(hw.ram[0x1234], 0x55);
+        map.write(&mut hw, 0x2345, 0x12);
+        assert_eq!(hw.ram[0x2345], 0x12);
+    }
+
+    #[test]
+    fn unmapped_and_open_bus_reads() {
+        let map = map(vec![(0x4000, 0x4fff, Region::OpenBus)]);
+        let mut hw = Hardware::new(0x800);
+        hw.data_bus = 0x42;
+        assert_eq!(map.read(&mut hw, 0x4321), 0x42);
+        assert_eq!(map.read(&mut hw, 0x0000), 0xff);
+        assert_eq!(map.read(&mut hw, 0xffff), 0xff);
+    }
+
+    #[test]
+    fn banked_rom_wraps_around() {
+        // three 4K banks behind a 4K window
+        let data: Vec<u8> = (0..0x3000).map(|i| (i / 0x1000) as u8 + 1).collect();
+        let map = map(vec![(0x8000, 0x8fff, Region::BankedRom(data))]);
+        let mut hw = Hardware::new(0x800);
+        for (bank, expected) in [(0, 1), (1, 2), (2, 3), (3, 1), (4, 2)] {
+            hw.bank = bank;
+            assert_eq!(map.read(&mut hw, 0x8000), expected);
+            assert_eq!(map.read(&mut hw, 0x8fff), expected);
+        }
+    }
+}
@@ -0,0 +1,621 @@
+use chess::{
+    Board, BoardBuilder, CastleRights, ChessMove, Color, File, Piece, Rank, Square, ALL_SQUARES,
+};
+
+// distances between two ranks of the board arrays we look for: plain 8x8,
+// 10x12 mailbox, 12x12 and 0x88
+const RANK_STRIDES: [usize; 4] = [8, 10, 12, 16];
+
+pub type Placement = [Option<(Piece, Color)>; 64];
+
+pub fn placement(board: &Board) -> Placement {
+    let mut res = [None; 64];
+    for sq in ALL_SQUARES {
+        if let (Some(piece), Some(color)) = (board.piece_on(sq), board.color_on(sq)) {
+            res[sq.to_index()] = Some((piece, color));
+        }
+    }
+    res
+}
+
+// Only the board array is decoded, not the firmware's piece list or its
+// castling and en passant state. So castling rights are a guess, granted
+// wherever king and rook still stand on their home squares, and the board
+// never has an en passant square.
+pub fn to_board(placement: &Placement, side_to_move: Color) -> Option<Board> {
+    let mut builder = BoardBuilder::new();
+    for sq in ALL_SQUARES {
+        if let Some((piece, color)) = placement[sq.to_index()] {
+            builder.piece(sq, piece, color);
+        }
+    }
+    builder.side_to_move(side_to_move);
+    for color in [Color::White, Color::Black] {
+        let home = |file, piece| {
+            placement[Square::make_square(color.to_my_backrank(), file).to_index()]
+                == Some((piece, color))
+        };
+        let rights = match (
+            home(File::E, Piece::King),
+            home(File::H, Piece::Rook),
+            home(File::A, Piece::Rook),
+        ) {
+            (true, true, true) => CastleRights::Both,
+            (true, true, false) => CastleRights::KingSide,
+            (true, false, true) => CastleRights::QueenSide,
+            _ => CastleRights::NoRights,
+        };
+        builder.castle_rights(color, rights);
+    }
+    Board::try_from(builder).ok()
+}
+
+// Whether `board` has just the castling rights its home squares imply, the
+// only ones a board written to RAM can carry.
+pub fn rights_implied(board: &Board) -> bool {
+    to_board(&placement(board), board.side_to_move()).is_some_and(|b| {
+        [Color::White, Color::Black]
+            .iter()
+            .all(|c| b.castle_rights(*c) == board.castle_rights(*c))
+    })
+}
+
+// The position right before the double step that allows en passant on
+// `board`, and that step. Playing it is how the firmware learns about it.
+pub fn before_double_push(board: &Board) -> Option<(Board, ChessMove)> {
+    let pawn = board.en_passant()?;
+    let pusher = !board.side_to_move();
+    let from = pawn.backward(pusher)?.backward(pusher)?;
+    let mut builder = BoardBuilder::from(board);
+    builder
+        .clear_square(pawn)
+        .piece(from, Piece::Pawn, pusher)
+        .side_to_move(pusher)
+        .en_passant(None);
+    let before = Board::try_from(builder).ok()?;
+    let push = ChessMove::new(from, pawn, None);
+    before.legal(push).then_some((before, push))
+}
+
+// Where and how the firmware keeps a board in its RAM. Nothing about this is
+// hard coded: it is learned from a freshly booted machine, where we know
+// exactly which piece stands on which square.
+#[derive(Clone, Copy, Debug)]
+pub struct BoardLayout {
+    pub base: usize,
+    pub rank_stride: usize,
+    // rank 8 comes first
+    pub flipped: bool,
+    codes: [[u8; 6]; 2],
+    empty: u8,
+}
+
+impl BoardLayout {
+    // Finds every array in `ram` that holds `board`. The board needs all twelve
+    // kinds of pieces on it, so each of them gets a code.
+    pub fn discover(ram: &[u8], board: &Board) -> Vec<BoardLayout> {
+        let mut found = Vec::new();
+        for rank_stride in RANK_STRIDES {
+            let span = 7 * rank_stride + 8;
+            for flipped in [false, true] {
+                for base in 0..=ram.len().saturating_sub(span) {
+                    if let Some(layout) = BoardLayout::learn(ram, board, base, rank_stride, flipped)
+                    {
+                        found.push(layout);
+                    }
+                }
+            }
+        }
+        found
+    }
+    fn learn(
+        ram: &[u8],
+        board: &Board,
+        base: usize,
+        rank_stride: usize,
+        flipped: bool,
+    ) -> Option<BoardLayout> {
+        let mut layout = BoardLayout {
+            base,
+            rank_stride,
+            flipped,
+            codes: [[0; 6]; 2],
+            empty: 0,
+        };
+        let mut codes = [[None; 6]; 2];
+        let mut empty = None;
+        for (sq, content) in ALL_SQUARES.iter().zip(placement(board)) {
+            let val = ram[layout.addr(*sq)];
+            let code = match content {
+                Some((piece, color)) => &mut codes[color.to_index()][piece.to_index()],
+                None => &mut empty,
+            };
+            match code {
+                None => *code = Some(val),
+                Some(c) if *c != val => return None,
+                _ => {}
+            }
+        }
+        let mut all = Vec::new();
+        for (c, color_codes) in codes.iter().enumerate() {
+            for (p, code) in color_codes.iter().enumerate() {
+                layout.codes[c][p] = (*code)?;
+                all.push(layout.codes[c][p]);
+            }
+        }
+        layout.empty = empty?;
+        all.push(layout.empty);
+        all.sort();
+        all.dedup();
+        // every kind of piece and the empty square need their own code
+        if all.len() != 13 {
+            return None;
+        }
+        Some(layout)
+    }
+    pub fn addr(&self, sq: Square) -> usize {
+        let rank = sq.get_rank().to_index();
+        let rank = if self.flipped { 7 - rank } else { rank };
+        self.base + rank * self.rank_stride + sq.get_file().to_index()
+    }
+    pub fn holds(&self, ram: &[u8], board: &Board) -> bool {
+        self.read(ram) == Some(placement(board))
+    }
+    // None if a square holds a code we don't know
+    pub fn read(&self, ram: &[u8]) -> Option<Placement> {
+        let mut res = [None; 64];
+        for sq in ALL_SQUARES {
+            let val = ram[self.addr(sq)];
+            if val == self.empty {
+                continue;
+            }
+            res[sq.to_index()] = Some(self.decode(val)?);
+        }
+        Some(res)
+    }
+    pub fn write(&self, ram: &mut [u8], board: &Board) {
+        for (sq, content) in ALL_SQUARES.iter().zip(placement(board)) {
+            ram[self.addr(*sq)] = match content {
+                Some((piece, color)) => self.codes[color.to_index()][piece.to_index()],
+                None => self.empty,
+            };
+        }
+    }
+    fn decode(&self, val: u8) -> Option<(Piece, Color)> {
+        for color in [Color::White, Color::Black] {
+            for piece in chess::ALL_PIECES {
+                if self.codes[color.to_index()][piece.to_index()] == val {
+                    return Some((piece, color));
+                }
+            }
+        }
+        None
+    }
+}
+
+// how the firmware numbers squares in its move variables
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SquareCoding {
+    // offset into the board array
+    Board,
+    // rank * 8 + file, with the ranks in board array order
+    Plain,
+}
+
+impl SquareCoding {
+    fn encode(&self, layout: &BoardLayout, sq: Square) -> u8 {
+        match self {
+            SquareCoding::Board => (layout.addr(sq) - layout.base) as u8,
+            SquareCoding::Plain => {
+                ((layout.addr(sq) - layout.base) / layout.rank_stride * 8
+                    + sq.get_file().to_index()) as u8
+            }
+        }
+    }
+    fn decode(&self, layout: &BoardLayout, val: u8) -> Option<Square> {
+        ALL_SQUARES
+            .iter()
+            .find(|sq| self.encode(layout, **sq) == val)
+            .copied()
+    }
+}
+
+// the most addresses we keep track of while learning the move variables
+const MAX_SLOT_CANDIDATES: usize = 4096;
+// how far apart from and to square, and two consecutive moves may be stored
+const MAX_SLOT_DISTANCE: usize = 16;
+
+// Where the firmware keeps the move it decided on and the reply it expects.
+// Learned by matching RAM against what the display shows for a few moves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MoveSlots {
+    pub coding: SquareCoding,
+    pub from: usize,
+    pub to: usize,
+    // distance between the best move and the expected reply
+    pub next: isize,
+}
+
+impl MoveSlots {
+    pub fn discover(
+        ram: &[u8],
+        layout: &BoardLayout,
+        best: ChessMove,
+        ponder: ChessMove,
+    ) -> Vec<MoveSlots> {
+        let mut found = Vec::new();
+        for coding in [SquareCoding::Board, SquareCoding::Plain] {
+            let from = coding.encode(layout, best.get_source());
+            let to = coding.encode(layout, best.get_dest());
+            for (f, _) in ram.iter().enumerate().filter(|(_, v)| **v == from) {
+                let lo = f.saturating_sub(MAX_SLOT_DISTANCE);
+                let hi = (f + MAX_SLOT_DISTANCE).min(ram.len() - 1);
+                for t in (lo..=hi).filter(|t| *t != f && ram[*t] == to) {
+                    for next in -(MAX_SLOT_DISTANCE as isize)..=MAX_SLOT_DISTANCE as isize {
+                        let slots = MoveSlots {
+                            coding,
+                            from: f,
+                            to: t,
+                            next,
+                        };
+                        if next != 0 && slots.matches(ram, layout, best, ponder) {
+                            found.push(slots);
+                            if found.len() > MAX_SLOT_CANDIDATES {
+                                return Vec::new();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+    pub fn matches(
+        &self,
+        ram: &[u8],
+        layout: &BoardLayout,
+        best: ChessMove,
+        ponder: ChessMove,
+    ) -> bool {
+        self.read(ram, layout) == (Some(squares(best)), Some(squares(ponder)))
+    }
+    pub fn read(&self, ram: &[u8], layout: &BoardLayout) -> (Option<Squares>, Option<Squares>) {
+        let at = |addr: isize| -> Option<Square> {
+            let val = *ram.get(usize::try_from(addr).ok()?)?;
+            self.coding.decode(layout, val)
+        };
+        let read_move = |offset: isize| -> Option<Squares> {
+            Some((
+                at(self.from as isize + offset)?,
+                at(self.to as isize + offset)?,
+            ))
+        };
+        (read_move(0), read_move(self.next))
+    }
+}
+
+pub type Squares = (Square, Square);
+
+// how the firmware may keep a number
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueCoding {
+    Byte,
+    WordLe,
+    WordBe,
+}
+
+// A number the INFO pages show, like the score or the search depth. Learned
+// like the move variables, by matching RAM against the display after a move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueSlot {
+    pub coding: ValueCoding,
+    pub addr: usize,
+}
+
+impl ValueSlot {
+    pub fn discover(ram: &[u8], value: i32) -> Vec<ValueSlot> {
+        let mut found = Vec::new();
+        for coding in [ValueCoding::Byte, ValueCoding::WordLe, ValueCoding::WordBe] {
+            for addr in 0..ram.len() {
+                let slot = ValueSlot { coding, addr };
+                if slot.read(ram) == Some(value) {
+                    found.push(slot);
+                    if found.len() > MAX_SLOT_CANDIDATES {
+                        return Vec::new();
+                    }
+                }
+            }
+        }
+        found
+    }
+    pub fn read(&self, ram: &[u8]) -> Option<i32> {
+        let byte = |i: usize| ram.get(self.addr + i).copied();
+        Some(match self.coding {
+            ValueCoding::Byte => byte(0)? as i8 as i32,
+            ValueCoding::WordLe => i16::from_le_bytes([byte(0)?, byte(1)?]) as i32,
+            ValueCoding::WordBe => i16::from_be_bytes([byte(0)?, byte(1)?]) as i32,
+        })
+    }
+}
+
+// The value once all candidates left agree on it. A small number kept in a
+// word reads the same as a byte, so those may never be told apart.
+pub fn read_learned(slots: &[ValueSlot], ram: &[u8]) -> Option<i32> {
+    let value = slots.first()?.read(ram)?;
+    slots
+        .iter()
+        .all(|s| s.read(ram) == Some(value))
+        .then_some(value)
+}
+
+fn squares(mov: ChessMove) -> Squares {
+    (mov.get_source(), mov.get_dest())
+}
+
+// Turns a from/to pair into a legal move on `board`, promoting to `promotion`
+// or a queen.
+pub fn legal_move(
+    board: &Board,
+    (from, to): Squares,
+    promotion: Option<Piece>,
+) -> Option<ChessMove> {
+    let promotion = if board.piece_on(from) == Some(Piece::Pawn)
+        && (to.get_rank() == Rank::First || to.get_rank() == Rank::Eighth)
+    {
+        Some(promotion.unwrap_or(Piece::Queen))
+    } else {
+        None
+    };
+    let mov = ChessMove::new(from, to, promotion);
+    if board.legal(mov) {
+        Some(mov)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const FILLER: u8 = 0xff;
+
+    fn layout(base: usize, rank_stride: usize, flipped: bool) -> BoardLayout {
+        let mut codes = [[0; 6]; 2];
+        for (p, code) in codes[0].iter_mut().enumerate() {
+            *code = p as u8 + 1;
+        }
+        for (p, code) in codes[1].iter_mut().enumerate() {
+            *code = p as u8 + 0x81;
+        }
+        BoardLayout {
+            base,
+            rank_stride,
+            flipped,
+            codes,
+            empty: 0,
+        }
+    }
+
+    fn ram_with(layout: &BoardLayout, board: &Board) -> Vec<u8> {
+        let mut ram = vec![FILLER; 0x800];
+        layout.write(&mut ram, board);
+        ram
+    }
+
+    fn after(moves: &[(Square, Square)]) -> Board {
+        moves.iter().fold(Board::default(), |b, (from, to)| {
+            b.make_move_new(ChessMove::new(*from, *to, None))
+        })
+    }
+
+    #[test]
+    fn finds_the_board_with_any_stride_either_way_up() {
+        // the start position reads the same upside down with the colors
+        // swapped, this one doesn't
+        let board = after(&[(Square::E2, Square::E4)]);
+        for rank_stride in RANK_STRIDES {
+            for flipped in [false, true] {
+                let ram = ram_with(&layout(0x123, rank_stride, flipped), &board);
+                let found = BoardLayout::discover(&ram, &board);
+                assert_eq!(found.len(), 1, "stride {rank_stride}, flipped {flipped}");
+                assert_eq!(found[0].base, 0x123);
+                assert_eq!(found[0].rank_stride, rank_stride);
+                assert_eq!(found[0].flipped, flipped);
+                assert!(found[0].holds(&ram, &board));
+                assert!(!found[0].holds(&ram, &Board::default()));
+            }
+        }
+    }
+
+    #[test]
+    fn needs_every_kind_of_piece_to_learn_the_codes() {
+        let board = Board::from_str("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let ram = ram_with(&layout(0x40, 8, false), &board);
+        assert!(BoardLayout::discover(&ram, &board).is_empty());
+    }
+
+    #[test]
+    fn unknown_codes_are_not_read() {
+        let l = layout(0x40, 10, false);
+        let mut ram = ram_with(&l, &Board::default());
+        assert_eq!(l.read(&ram), Some(placement(&Board::default())));
+        ram[l.addr(Square::D4)] = 0x42;
+        assert_eq!(l.read(&ram), None);
+    }
+
+    #[test]
+    fn guesses_castling_rights_from_the_home_squares() {
+        let board = to_board(&placement(&Board::default()), Color::White).unwrap();
+        assert_eq!(board, Board::default());
+
+        let board = Board::from_str("r3k3/8/8/8/8/8/8/4K2R b - - 0 1").unwrap();
+        let guessed = to_board(&placement(&board), Color::Black).unwrap();
+        assert_eq!(guessed.castle_rights(Color::White), CastleRights::KingSide);
+        assert_eq!(guessed.castle_rights(Color::Black), CastleRights::QueenSide);
+        assert_eq!(guessed.side_to_move(), Color::Black);
+
+        // the en passant square is always lost
+        let board = Board::from_str("4k3/8/8/8/3pP3/8/8/4K3 b - e3 0 1").unwrap();
+        assert!(board.en_passant().is_some());
+        assert_eq!(
+            to_board(&placement(&board), Color::Black)
+                .unwrap()
+                .en_passant(),
+            None
+        );
+    }
+
+    #[test]
+    fn knows_which_castling_rights_survive_ram() {
+        assert!(rights_implied(&Board::default()));
+        let board = Board::from_str("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        assert!(rights_implied(&board));
+        let board = Board::from_str("r3k2r/8/8/8/8/8/8/R3K2R w Kq - 0 1").unwrap();
+        assert!(!rights_implied(&board));
+        let board = Board::from_str("r3k2r/8/8/8/8/8/8/R3K2R w - - 0 1").unwrap();
+        assert!(!rights_implied(&board));
+    }
+
+    #[test]
+    fn goes_back_before_the_double_step() {
+        let board = Board::from_str("4k3/8/8/8/3pP3/8/8/4K3 b - e3 0 1").unwrap();
+        let (before, push) = before_double_push(&board).unwrap();
+        assert_eq!(push, ChessMove::new(Square::E2, Square::E4, None));
+        assert_eq!(before.side_to_move(), Color::White);
+        assert_eq!(before.make_move_new(push), board);
+
+        let board = Board::from_str("4k3/8/8/3Pp3/8/8/8/4K3 w - e6 0 1").unwrap();
+        let (before, push) = before_double_push(&board).unwrap();
+        assert_eq!(push, ChessMove::new(Square::E7, Square::E5, None));
+        assert_eq!(before.make_move_new(push), board);
+
+        assert_eq!(before_double_push(&Board::default()), None);
+    }
+
+    #[test]
+    fn finds_the_move_slots() {
+        let l = layout(0x100, 16, true);
+        let best = ChessMove::new(Square::E2, Square::E4, None);
+        let ponder = ChessMove::new(Square::E7, Square::E5, None);
+        for coding in [SquareCoding::Board, SquareCoding::Plain] {
+            let mut ram = ram_with(&l, &Board::default());
+            let expected = MoveSlots {
+                coding,
+                from: 0x300,
+                to: 0x301,
+                next: 2,
+            };
+            ram[0x300] = coding.encode(&l, Square::E2);
+            ram[0x301] = coding.encode(&l, Square::E4);
+            ram[0x302] = coding.encode(&l, Square::E7);
+            ram[0x303] = coding.encode(&l, Square::E5);
+            let found = MoveSlots::discover(&ram, &l, best, ponder);
+            assert!(found.contains(&expected), "{coding:?}");
+            assert!(found.iter().all(|s| s.matches(&ram, &l, best, ponder)));
+            assert_eq!(
+                expected.read(&ram, &l),
+                (
+                    Some((Square::E2, Square::E4)),
+                    Some((Square::E7, Square::E5))
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn squares_are_coded_as_board_offsets_or_plainly() {
+        let l = layout(0x100, 16, true);
+        // rank 8 comes first
+        assert_eq!(SquareCoding::Board.encode(&l, Square::A8), 0);
+        assert_eq!(SquareCoding::Board.encode(&l, Square::E2), 6 * 16 + 4);
+        assert_eq!(SquareCoding::Plain.encode(&l, Square::E2), 6 * 8 + 4);
+        assert_eq!(SquareCoding::Board.decode(&l, 6 * 16 + 4), Some(Square::E2));
+        // off the board in a 0x88 array
+        assert_eq!(SquareCoding::Board.decode(&l, 0x08), None);
+    }
+
+    #[test]
+    fn reads_values_as_bytes_and_words() {
+        let mut ram = vec![0x55; 0x100];
+        ram[0x40..0x42].copy_from_slice(&(-300i16).to_le_bytes());
+        let found = ValueSlot::discover(&ram, -300);
+        assert_eq!(
+            found,
+            vec![ValueSlot {
+                coding: ValueCoding::WordLe,
+                addr: 0x40
+            }]
+        );
+        assert_eq!(read_learned(&found, &ram), Some(-300));
+
+        ram[0x80..0x82].copy_from_slice(&(-300i16).to_be_bytes());
+        let slot = ValueSlot {
+            coding: ValueCoding::WordBe,
+            addr: 0x80,
+        };
+        assert_eq!(slot.read(&ram), Some(-300));
+        // running off the end of RAM
+        let slot = ValueSlot {
+            coding: ValueCoding::WordLe,
+            addr: 0xff,
+        };
+        assert_eq!(slot.read(&ram), None);
+    }
+
+    #[test]
+    fn learned_values_need_all_candidates_to_agree() {
+        let mut ram = vec![0; 0x100];
+        ram[0x10] = 5;
+        let slots = [
+            ValueSlot {
+                coding: ValueCoding::Byte,
+                addr: 0x10,
+            },
+            ValueSlot {
+                coding: ValueCoding::WordLe,
+                addr: 0x10,
+            },
+        ];
+        // a small number in a word reads the same as a byte
+        assert_eq!(read_learned(&slots, &ram), Some(5));
+        ram[0x11] = 1;
+        assert_eq!(read_learned(&slots, &ram), None);
+        assert_eq!(read_learned(&[], &ram), None);
+    }
+
+    #[test]
+    fn gives_up_on_too_many_candidates() {
+        let ram = vec![0; 0x2000];
+        assert!(ValueSlot::discover(&ram, 0).is_empty());
+    }
+
+    #[test]
+    fn makes_legal_moves_from_squares() {
+        let board = Board::default();
+        assert_eq!(
+            legal_move(&board, (Square::G1, Square::F3), None),
+            Some(ChessMove::new(Square::G1, Square::F3, None))
+        );
+        assert_eq!(legal_move(&board, (Square::E2, Square::E5), None), None);
+        // black's move on white's turn
+        assert_eq!(legal_move(&board, (Square::E7, Square::E5), None), None);
+
+        let board = Board::from_str("3qk3/4P3/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        assert_eq!(
+            legal_move(&board, (Square::E7, Square::D8), None),
+            Some(ChessMove::new(Square::E7, Square::D8, Some(Piece::Queen)))
+        );
+        assert_eq!(
+            legal_move(&board, (Square::E7, Square::D8), Some(Piece::Knight)),
+            Some(ChessMove::new(Square::E7, Square::D8, Some(Piece::Knight)))
+        );
+        // only pawns promote
+        let board = Board::from_str("4k3/8/8/8/8/8/8/R3K3 w - - 0 1").unwrap();
+        assert_eq!(
+            legal_move(&board, (Square::A1, Square::A8), Some(Piece::Queen)),
+            Some(ChessMove::new(Square::A1, Square::A8, None))
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{Error, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use sha1_smol::Sha1;
+use zip::ZipArchive;
+
+// colon separated list of directories and zip files to look for ROMs in
+pub const ROM_PATH_VAR: &str = "MEPHISTO_ROM_PATH";
+// checksums of verified dumps, looked for in every directory of the search path
+pub const KNOWN_ROMS_FILE: &str = "known_roms.txt";
+
+// Where ROM images come from. Explicit files and buffers win, then the search
+// path is tried in order: the given directories and zip sets, the ones from
+// the environment, the working directory and the XDG data directory.
+pub struct RomLoader {
+    files: HashMap<String, PathBuf>,
+    buffers: HashMap<String, Vec<u8>>,
+    search_path: Vec<PathBuf>,
+    known: Vec<KnownRom>,
+}
+
+impl RomLoader {
+    pub fn new(paths: &[PathBuf]) -> RomLoader {
+        let mut search_path = paths.to_vec();
+        if let Some(var) = env::var_os(ROM_PATH_VAR) {
+            search_path.extend(env::split_paths(&var));
+        }
+        search_path.push(PathBuf::from("."));
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".local/share")));
+        if let Some(data_home) = data_home {
+            search_path.push(data_home.join("mephisto-emu"));
+        }
+        let mut known: Vec<KnownRom> = KNOWN_ROMS.iter().map(KnownRom::from_table).collect();
+        for dir in search_path.iter().filter(|d| !is_zip(d)) {
+            if let Ok(list) = fs::read_to_string(dir.join(KNOWN_ROMS_FILE)) {
+                known.extend(parse_known_roms(&list, &dir.join(KNOWN_ROMS_FILE)));
+            }
+        }
+        RomLoader {
+            files: HashMap::new(),
+            buffers: HashMap::new(),
+            search_path,
+            known,
+        }
+    }
+    // use `path` for the ROM called `name`
+    pub fn with_file(mut self, name: &str, path: PathBuf) -> RomLoader {
+        self.files.insert(name.to_lowercase(), path);
+        self
+    }
+    // for embedding ROMs and for tests
+    #[allow(dead_code)]
+    pub fn with_buffer(mut self, name: &str, data: &[u8]) -> RomLoader {
+        self.buffers.insert(name.to_lowercase(), data.to_vec());
+        self
+    }
+    // take `rom` as a known dump
+    #[allow(dead_code)]
+    pub fn with_known(mut self, rom: KnownRom) -> RomLoader {
+        self.known.push(rom);
+        self
+    }
+    fn identify(&self, size: usize, crc32: u32, sha1: &str) -> Option<KnownRom> {
+        self.known
+            .iter()
+            .find(|k| k.size == size && k.crc32 == crc32 && k.sha1.eq_ignore_ascii_case(sha1))
+            .cloned()
+    }
+    // the contents of ROM `name` and where they came from
+    pub fn read(&self, name: &str) -> Result<(Vec<u8>, String), Error> {
+        let key = name.to_lowercase();
+        if let Some(path) = self.files.get(&key) {
+            let data = fs::read(path)
+                .map_err(|e| Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            return Ok((data, path.display().to_string()));
+        }
+        if let Some(data) = self.buffers.get(&key) {
+            return Ok((data.clone(), format!("{name} from memory")));
+        }
+        for dir in &self.search_path {
+            let found = if is_zip(dir) {
+                read_from_zip(dir, &key, &self.known)
+            } else {
+                read_from_dir(dir, name)
+            };
+            if let Some(found) = found {
+                return Ok(found);
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Could not find {name}! Looked in {}",
+                self.search_path
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ))
+    }
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+fn read_from_dir(dir: &Path, name: &str) -> Option<(Vec<u8>, String)> {
+    let path = dir.join(name);
+    let data = fs::read(&path).ok()?;
+    Some((data, path.display().to_string()))
+}
+
+// File names in zip sets are matched without regard to case. Failing that, a
+// member is taken if the zip directory gives it the CRC32 of a dump we know
+// under that name, as MAME sets name their files after the chips. Those come
+// from the known_roms.txt files, see KNOWN_ROMS.
+fn read_from_zip(path: &Path, key: &str, known: &[KnownRom]) -> Option<(Vec<u8>, String)> {
+    let mut archive = ZipArchive::new(fs::File::open(path).ok()?).ok()?;
+    let base_name = |name: &str| name.rsplit('/').next().unwrap_or(name).to_lowercase();
+    let mut found = None;
+    for i in 0..archive.len() {
+        // one broken entry doesn't spoil the rest of the set
+        let Ok(file) = archive.by_index_raw(i) else {
+            continue;
+        };
+        if base_name(file.name()) == key {
+            found = Some(i);
+            break;
+        }
+        let known_here = known.iter().any(|k| {
+            k.file.eq_ignore_ascii_case(key)
+                && k.crc32 == file.crc32()
+                && k.size as u64 == file.size()
+        });
+        if known_here && found.is_none() {
+            found = Some(i);
+        }
+    }
+    let mut file = archive.by_index(found?).ok()?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).ok()?;
+    Some((data, format!("{} in {}", file.name(), path.display())))
+}
+
+// A dump we know, so we can tell which program version is running.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownRom {
+    pub name: String,
+    // the name it is loaded as, like MM2.rom
+    pub file: String,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: String,
+}
+
+impl KnownRom {
+    fn from_table(&(name, file, size, crc32, sha1): &(&str, &str, usize, u32, &str)) -> KnownRom {
+        KnownRom {
+            name: name.to_string(),
+            file: file.to_string(),
+            size,
+            crc32,
+            sha1: sha1.to_string(),
+        }
+    }
+}
+
+// Name, file, size, CRC32 and SHA1 of dumps we ship the checksums for. Only
+// add checksums taken from a verified dump here, the rest goes into a
+// KNOWN_ROMS_FILE next to the ROMs.
+const KNOWN_ROMS: &[(&str, &str, usize, u32, &str)] = &[];
+
+// A KNOWN_ROMS_FILE has one dump per line: the file it is loaded as, its size,
+// CRC32 and SHA1 (as MAME's -listxml gives them) and then its name, like
+// `MM2.rom 32768 0123abcd <sha1> MM II v4.00`. # starts a comment.
+fn parse_known_roms(list: &str, path: &Path) -> Vec<KnownRom> {
+    let mut known = Vec::new();
+    for line in list
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or("").trim())
+    {
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let rom = match fields[..] {
+            [file, size, crc32, sha1, ref name @ ..] if !name.is_empty() && sha1.len() == 40 => {
+                match (size.parse(), u32::from_str_radix(crc32, 16)) {
+                    (Ok(size), Ok(crc32)) => Some(KnownRom {
+                        name: name.join(" "),
+                        file: file.to_string(),
+                        size,
+                        crc32,
+                        sha1: sha1.to_lowercase(),
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match rom {
+            Some(rom) => known.push(rom),
+            None => out!("info Debug {}: can't read {line}", path.display()),
+        }
+    }
+    known
+}
+
+// What we found out about a loaded image.
+pub struct RomInfo {
+    pub path: String,
+    pub crc32: u32,
+    pub sha1: String,
+    pub known: Option<KnownRom>,
+}
+
+impl RomInfo {
+    fn new(roms: &RomLoader, path: &str, data: &[u8]) -> RomInfo {
+        let crc32 = crc32fast::hash(data);
+        let sha1 = Sha1::from(data).digest().to_string();
+        let known = roms.identify(data.len(), crc32, &sha1);
+        RomInfo {
+            path: path.to_string(),
+            crc32,
+            sha1,
+            known,
+        }
+    }
+}
+
+// swaps every pair of bytes, the usual mistake with 16 bit eprom readers
+fn byte_swapped(data: &[u8]) -> Vec<u8> {
+    data.chunks(2)
+        .flat_map(|c| c.iter().rev())
+        .copied()
+        .collect()
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Loads a ROM image of exactly `size` bytes and tells what it is. Dumps that
+// are split in two or byte swapped are refused with a hint on how to fix them.
+pub fn load_rom(roms: &RomLoader, name: &str, size: usize) -> Result<(Vec<u8>, RomInfo), Error> {
+    let (data, path) = roms.read(name)?;
+    let path = path.as_str();
+    if data.len() * 2 == size {
+        return Err(invalid(format!(
+            "{path} has {} bytes, half of the {size} expected. Is it one half of a split dump? Join both halves into one file.",
+            data.len()
+        )));
+    }
+    if data.len() != size {
+        return Err(invalid(format!(
+            "{path} has {} bytes, expected {size}!",
+            data.len()
+        )));
+    }
+    let info = RomInfo::new(roms, path, &data);
+    if info.known.is_none() {
+        let swapped = byte_swapped(&data);
+        let swapped_info = RomInfo::new(roms, path, &swapped);
+        if let Some(known) = swapped_info.known {
+            return Err(invalid(format!(
+                "{path} is a byte swapped dump of {}, swap every pair of bytes!",
+                known.name
+            )));
+        }
+        out!(
+            "info Debug unknown ROM {path}, crc32 {:08x} sha1 {}",
+            info.crc32,
+            info.sha1
+        );
+    }
+    Ok((data, info))
+}
+
+// The program ROM sits at the top of the address space, so its reset vector
+// has to point into it. Catches byte swapped dumps we don't know yet.
+pub fn check_program(data: &[u8], base: u16, info: &RomInfo) -> Result<(), Error> {
+    let vector = |d: &[u8]| u16::from_le_bytes([d[d.len() - 4], d[d.len() - 3]]);
+    if info.known.is_some() || vector(data) >= base {
+        return Ok(());
+    }
+    if vector(&byte_swapped(data)) >= base {
+        return Err(invalid(format!(
+            "{}: the reset vector points outside the ROM, but would fit if every pair of bytes was swapped. Is it a byte swapped dump?",
+            info.path
+        )));
+    }
+    Err(invalid(format!(
+        "{}: the reset vector {:04X} points outside the ROM, is this the right file?",
+        info.path,
+        vector(data)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    // a zip set in the temp directory holding `files`
+    fn zip_set(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (file, data) in files {
+            let options =
+                SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+            zip.start_file(*file, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let path = env::temp_dir().join(format!("mephisto-{}-{name}.zip", std::process::id()));
+        fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        path
+    }
+
+    fn known(file: &str, data: &[u8]) -> KnownRom {
+        KnownRom {
+            name: "MM II test".to_string(),
+            file: file.to_string(),
+            size: data.len(),
+            crc32: crc32fast::hash(data),
+            sha1: Sha1::from(data).digest().to_string(),
+        }
+    }
+
+    // a program ROM whose reset vector points into it
+    fn program() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..0x8000).map(|i| (i * 7 + i / 256) as u8).collect();
+        data[0x7ffc..0x7ffe].copy_from_slice(&0x8000u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn identifies_known_dumps() {
+        let data = program();
+        let roms = RomLoader::new(&[])
+            .with_buffer("MM2.rom", &data)
+            .with_known(known("MM2.rom", &data));
+        let (_, info) = load_rom(&roms, "MM2.rom", 0x8000).unwrap();
+        assert_eq!(info.known.unwrap().name, "MM II test");
+        // the same dump with its bytes swapped is refused
+        let roms = RomLoader::new(&[])
+            .with_buffer("MM2.rom", &byte_swapped(&data))
+            .with_known(known("MM2.rom", &data));
+        let err = load_rom(&roms, "MM2.rom", 0x8000).err().unwrap();
+        assert!(err.to_string().contains("byte swapped dump of MM II test"));
+    }
+
+    #[test]
+    fn reads_the_known_roms_file() {
+        let data = program();
+        let dir = env::temp_dir().join(format!("mephisto-{}-known", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(KNOWN_ROMS_FILE),
+            format!(
+                "# verified dumps\nMM2.rom {} {:08X} {} MM II test # from MAME\nbroken line\n",
+                data.len(),
+                crc32fast::hash(&data),
+                Sha1::from(&data).digest()
+            ),
+        )
+        .unwrap();
+        let roms = RomLoader::new(std::slice::from_ref(&dir));
+        assert_eq!(roms.known.len(), KNOWN_ROMS.len() + 1);
+        assert_eq!(roms.known.last(), Some(&known("MM2.rom", &data)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_from_buffers() {
+        let roms = RomLoader::new(&[]).with_buffer("MM2.rom", &[1, 2, 3]);
+        let (data, from) = roms.read("mm2.ROM").unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        assert!(from.contains("from memory"));
+    }
+
+    #[test]
+    fn reads_zip_members_by_name() {
+        let path = zip_set("names", &[("set/HG240.ROM", &[4, 5]), ("mm2.rom", &[6])]);
+        let roms = RomLoader::new(std::slice::from_ref(&path));
+        assert_eq!(roms.read("hg240.rom").unwrap().0, [4, 5]);
+        assert_eq!(roms.read("MM2.rom").unwrap().0, [6]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_zip_members_by_crc() {
+        let program = [0xea; 16];
+        let path = zip_set(
+            "crc",
+            &[("chip_u3.bin", &[0; 16]), ("chip_u4.bin", &program)],
+        );
+        let roms =
+            RomLoader::new(std::slice::from_ref(&path)).with_known(known("MM2.rom", &program));
+        let (data, from) = roms.read("MM2.rom").unwrap();
+        assert_eq!(data, program);
+        assert!(from.starts_with("chip_u4.bin"));
+        assert!(read_from_zip(&path, "hg240.rom", &roms.known).is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
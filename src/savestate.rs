@@ -0,0 +1,336 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Read, Write},
+    str::FromStr,
+};
+
+use chess::{Board, Square};
+use w65c02s::{State, System, W65C02S};
+
+use crate::modular::MAX_RAM_SIZE;
+
+const MAGIC: &[u8; 8] = b"MEPHSAVE";
+const VERSION: u16 = 3;
+
+// Everything that changes while the machine runs. The ROMs are not part of
+// it, so a state has to be loaded into an emulator with the same ROM set.
+#[derive(Clone)]
+pub struct MachineState {
+    // the module and the CRC32 of its program, to tell which ROM set it needs
+    pub module: String,
+    pub program_crc32: u32,
+    pub cpu: W65C02S,
+    pub ram: Vec<u8>,
+    pub cur_bitboard: [u8; 8],
+    pub pressed_keys: [[bool; 8]; 2],
+    pub outlatch: [bool; 8],
+    pub mux: usize,
+    pub display: [u8; 4],
+    pub last_display: [u8; 4],
+    pub display_pos: i8,
+    pub board_leds: [u8; 8],
+    pub board_leds_big: [u8; 64],
+    pub irq_done: bool,
+    pub led_square: Square,
+    pub bank: u8,
+    pub cycles: u64,
+    pub next_irq: u64,
+    pub cur_board: Board,
+    pub difficulty: u8,
+}
+
+impl MachineState {
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+        fs::write(path, out)
+    }
+    pub fn load(path: &str) -> Result<MachineState, Error> {
+        let data = fs::read(path)?;
+        MachineState::read(&mut data.as_slice())
+    }
+    pub fn write(&self, w: &mut impl Write) -> Result<(), Error> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[self.module.len() as u8])?;
+        w.write_all(self.module.as_bytes())?;
+        w.write_all(&self.program_crc32.to_le_bytes())?;
+        // cpu
+        w.write_all(&self.cpu.get_pc().to_le_bytes())?;
+        w.write_all(&[
+            self.cpu.get_a(),
+            self.cpu.get_x(),
+            self.cpu.get_y(),
+            self.cpu.get_s(),
+            self.cpu.get_p(),
+            match self.cpu.get_state() {
+                State::HasBeenReset => 0,
+                State::Running => 1,
+                State::AwaitingInterrupt => 2,
+                State::Stopped => 3,
+            },
+        ])?;
+        // system
+        w.write_all(&(self.ram.len() as u32).to_le_bytes())?;
+        w.write_all(&self.ram)?;
+        w.write_all(&self.cur_bitboard)?;
+        for bank in self.pressed_keys {
+            w.write_all(&[pack_bits(&bank)])?;
+        }
+        w.write_all(&[pack_bits(&self.outlatch), self.mux as u8])?;
+        w.write_all(&self.display)?;
+        w.write_all(&self.last_display)?;
+        w.write_all(&[self.display_pos as u8])?;
+        w.write_all(&self.board_leds)?;
+        w.write_all(&self.board_leds_big)?;
+        w.write_all(&[self.irq_done as u8, self.led_square.to_int(), self.bank])?;
+        w.write_all(&self.cycles.to_le_bytes())?;
+        w.write_all(&self.next_irq.to_le_bytes())?;
+        // emulator
+        let fen = self.cur_board.to_string();
+        w.write_all(&[fen.len() as u8])?;
+        w.write_all(fen.as_bytes())?;
+        w.write_all(&[self.difficulty])
+    }
+    pub fn read(r: &mut impl Read) -> Result<MachineState, Error> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a save state!"));
+        }
+        let version = u16::from_le_bytes(read_array(r)?);
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported save state version {version}!"),
+            ));
+        }
+        let [module_len] = read_array(r)?;
+        let mut module = vec![0; module_len as usize];
+        r.read_exact(&mut module)?;
+        let module = String::from_utf8(module)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid module name!"))?;
+        let program_crc32 = u32::from_le_bytes(read_array(r)?);
+        let pc = u16::from_le_bytes(read_array(r)?);
+        let [a, x, y, s, p, state] = read_array(r)?;
+        let state = match state {
+            0 => State::HasBeenReset,
+            1 => State::Running,
+            2 => State::AwaitingInterrupt,
+            3 => State::Stopped,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid cpu state!")),
+        };
+        let mut cpu = restore_cpu(pc, state);
+        cpu.set_a(a);
+        cpu.set_x(x);
+        cpu.set_y(y);
+        cpu.set_s(s);
+        cpu.set_p(p);
+        let ram_len = u32::from_le_bytes(read_array(r)?) as usize;
+        if ram_len > MAX_RAM_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt save state!"));
+        }
+        let mut ram = vec![0; ram_len];
+        r.read_exact(&mut ram)?;
+        let cur_bitboard = read_array(r)?;
+        let [keys0, keys1] = read_array(r)?;
+        let [outlatch, mux] = read_array(r)?;
+        let display = read_array(r)?;
+        let last_display = read_array(r)?;
+        let [display_pos] = read_array(r)?;
+        let board_leds = read_array(r)?;
+        let board_leds_big = read_array(r)?;
+        let [irq_done, led_square, bank] = read_array(r)?;
+        let cycles = u64::from_le_bytes(read_array(r)?);
+        let next_irq = u64::from_le_bytes(read_array(r)?);
+        let [fen_len] = read_array(r)?;
+        let mut fen = vec![0; fen_len as usize];
+        r.read_exact(&mut fen)?;
+        let cur_board = String::from_utf8(fen)
+            .ok()
+            .and_then(|f| Board::from_str(&f).ok())
+            .ok_or(Error::new(ErrorKind::InvalidData, "Invalid board!"))?;
+        let [difficulty] = read_array(r)?;
+        if mux > 7 || led_square > 63 || !(0..4).contains(&(display_pos as i8)) {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt save state!"));
+        }
+        Ok(MachineState {
+            module,
+            program_crc32,
+            cpu,
+            ram,
+            cur_bitboard,
+            pressed_keys: [unpack_bits(keys0), unpack_bits(keys1)],
+            outlatch: unpack_bits(outlatch),
+            mux: mux as usize,
+            display,
+            last_display,
+            display_pos: display_pos as i8,
+            board_leds,
+            board_leds_big,
+            irq_done: irq_done != 0,
+            led_square: unsafe { Square::new(led_square) },
+            bank,
+            cycles,
+            next_irq,
+            cur_board,
+            difficulty,
+        })
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N], Error> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn pack_bits(bits: &[bool; 8]) -> u8 {
+    bits.iter()
+        .enumerate()
+        .fold(0, |acc, (i, b)| acc | ((*b as u8) << i))
+}
+
+fn unpack_bits(byte: u8) -> [bool; 8] {
+    let mut res = [false; 8];
+    for (i, b) in res.iter_mut().enumerate() {
+        *b = byte & (1 << i) != 0;
+    }
+    res
+}
+
+// Feeds a fixed byte to every read, used to steer a fresh cpu into a state.
+struct Feeder {
+    byte: u8,
+}
+
+impl System for Feeder {
+    fn read(&mut self, _cpu: &mut W65C02S, _addr: u16) -> u8 {
+        self.byte
+    }
+    fn write(&mut self, _cpu: &mut W65C02S, _addr: u16, _value: u8) {}
+}
+
+// The cpu only exposes its registers, not its run state, so we walk a fresh
+// one through the reset sequence and a WAI/STP if needed.
+fn restore_cpu(pc: u16, state: State) -> W65C02S {
+    let mut cpu = W65C02S::new();
+    if state == State::HasBeenReset {
+        cpu.set_pc(pc);
+        return cpu;
+    }
+    cpu.step(&mut Feeder { byte: 0 });
+    match state {
+        State::AwaitingInterrupt => {
+            cpu.step(&mut Feeder { byte: 0xcb });
+        }
+        State::Stopped => {
+            cpu.step(&mut Feeder { byte: 0xdb });
+        }
+        _ => {}
+    }
+    cpu.set_pc(pc);
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(cpu_state: State) -> MachineState {
+        let mut cpu = restore_cpu(0x8123, cpu_state);
+        cpu.set_a(1);
+        cpu.set_x(2);
+        cpu.set_y(3);
+        cpu.set_s(0xfd);
+        cpu.set_p(0x24);
+        MachineState {
+            module: "MM2".to_string(),
+            program_crc32: 0x1234_5678,
+            cpu,
+            ram: (0..0x800).map(|i| i as u8).collect(),
+            cur_bitboard: [0xff, 0xff, 0, 0, 0, 0, 0xff, 0xff],
+            pressed_keys: [unpack_bits(0x05), unpack_bits(0x80)],
+            outlatch: unpack_bits(0x41),
+            mux: 3,
+            display: [1, 2, 3, 4],
+            last_display: [5, 6, 7, 8],
+            display_pos: 2,
+            board_leds: [9; 8],
+            board_leds_big: [1; 64],
+            irq_done: false,
+            led_square: Square::E4,
+            bank: 2,
+            cycles: 123_456_789,
+            next_irq: 123_460_000,
+            cur_board: Board::from_str("4k3/8/8/8/8/8/4P3/4K3 b - - 0 1").unwrap(),
+            difficulty: 4,
+        }
+    }
+
+    fn bytes(state: &MachineState) -> Vec<u8> {
+        let mut out = Vec::new();
+        state.write(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip_keeps_every_cpu_state() {
+        for cpu_state in [
+            State::HasBeenReset,
+            State::Running,
+            State::AwaitingInterrupt,
+            State::Stopped,
+        ] {
+            let saved = state(cpu_state);
+            let data = bytes(&saved);
+            let loaded = MachineState::read(&mut data.as_slice()).unwrap();
+            assert_eq!(loaded.cpu.get_state(), cpu_state);
+            assert_eq!(loaded.cpu.get_pc(), 0x8123);
+            assert_eq!(
+                (
+                    loaded.cpu.get_a(),
+                    loaded.cpu.get_x(),
+                    loaded.cpu.get_y(),
+                    loaded.cpu.get_s()
+                ),
+                (1, 2, 3, 0xfd)
+            );
+            assert_eq!(loaded.cur_board, saved.cur_board);
+            assert_eq!(
+                (loaded.module.as_str(), loaded.program_crc32),
+                ("MM2", 0x1234_5678)
+            );
+            assert_eq!(bytes(&loaded), data);
+        }
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = bytes(&state(State::Running));
+        data[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let err = MachineState::read(&mut data.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("version"));
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let data = bytes(&state(State::Running));
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] = b'X';
+        assert!(MachineState::read(&mut wrong_magic.as_slice()).is_err());
+        let truncated = &data[..data.len() - 10];
+        assert!(MachineState::read(&mut &truncated[..]).is_err());
+        let bad_mux = MachineState {
+            mux: 9,
+            ..state(State::Running)
+        };
+        assert!(MachineState::read(&mut bytes(&bad_mux).as_slice()).is_err());
+        // no allocating whatever RAM size the file asks for
+        let huge_ram = MachineState {
+            ram: vec![0; MAX_RAM_SIZE + 1],
+            ..state(State::Running)
+        };
+        assert!(MachineState::read(&mut bytes(&huge_ram).as_slice()).is_err());
+    }
+}
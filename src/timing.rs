@@ -0,0 +1,166 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+// MM II runs its R65C02 straight off a 3.7MHz crystal
+pub const MM2_CRYSTAL_HZ: u64 = 3_700_000;
+// the IRQ is taken from Q13 of the 4020 counter clocked by the cpu
+pub const MM2_IRQ_DIVIDER: u64 = 0x2000;
+// MM IV has a 4.9152MHz crystal, which gives an even 600Hz timer interrupt
+pub const MM4_CRYSTAL_HZ: u64 = 4_915_200;
+pub const MM4_IRQ_DIVIDER: u64 = 0x2000;
+// MM V runs at 5MHz
+pub const MM5_CRYSTAL_HZ: u64 = 5_000_000;
+pub const MM5_IRQ_DIVIDER: u64 = 0x2000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    pub crystal_hz: u64,
+    pub irq_divider: u64,
+}
+
+impl Timing {
+    pub fn new(crystal_hz: u64, irq_divider: u64) -> Timing {
+        Timing {
+            crystal_hz,
+            irq_divider,
+        }
+    }
+    // cpu cycles between two timer interrupts
+    pub fn irq_period(&self) -> u64 {
+        self.irq_divider
+    }
+    pub fn irq_hz(&self) -> f64 {
+        self.crystal_hz as f64 / self.irq_divider as f64
+    }
+    pub fn cycles_to_duration(&self, cycles: u64) -> Duration {
+        Duration::from_nanos((cycles as u128 * 1_000_000_000 / self.crystal_hz as u128) as u64)
+    }
+    pub fn duration_to_cycles(&self, dur: Duration) -> u64 {
+        (dur.as_nanos() * self.crystal_hz as u128 / 1_000_000_000) as u64
+    }
+}
+
+// moves we plan for when the gui doesn't say how many are left
+const MOVES_TO_GO: u32 = 30;
+// kept back on every move for pressing ENT and reading the move off the board
+const MOVE_OVERHEAD: Duration = Duration::from_millis(1000);
+
+// How long to think on a move with `left` on the clock. The time is spread
+// over the moves to the next control and most of the increment is added, but
+// no move may take more than half of what is left.
+pub fn move_budget(left: Duration, inc: Duration, moves_to_go: Option<u32>) -> Duration {
+    let to_go = moves_to_go.filter(|n| *n > 0).unwrap_or(MOVES_TO_GO);
+    let budget = left / to_go + inc * 3 / 4;
+    budget.min(left / 2).saturating_sub(MOVE_OVERHEAD)
+}
+
+// don't bother sleeping for less than this
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+// if the host falls behind by more than this we stop trying to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// How emulated time relates to wall time. Deadlines are always measured in
+// emulated time, so a search gets the same amount of work regardless of how
+// busy the host is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockPolicy {
+    // emulated time follows the wall clock, like the real hardware
+    RealTime,
+    // run as fast as the host allows
+    Fast,
+    // run n times faster than the real hardware, like the accelerated modules
+    Scaled(u32),
+}
+
+impl ClockPolicy {
+    // emulated time granted per unit of wall time
+    pub fn scale(&self) -> u32 {
+        match self {
+            ClockPolicy::RealTime | ClockPolicy::Fast => 1,
+            ClockPolicy::Scaled(n) => *n,
+        }
+    }
+}
+
+// Holds the emulated cpu to the pace required by the clock policy.
+pub struct Throttle {
+    policy: ClockPolicy,
+    anchor: Option<(Instant, u64)>,
+}
+
+impl Throttle {
+    pub fn new(policy: ClockPolicy) -> Throttle {
+        Throttle {
+            policy,
+            anchor: None,
+        }
+    }
+    pub fn policy(&self) -> ClockPolicy {
+        self.policy
+    }
+    pub fn set_policy(&mut self, policy: ClockPolicy) {
+        self.policy = policy;
+        self.anchor = None;
+    }
+    // restart pacing, e.g. after the emulator sat idle waiting for input
+    pub fn reset(&mut self) {
+        self.anchor = None;
+    }
+    pub fn pace(&mut self, timing: &Timing, cycles: u64) {
+        let speed = match self.policy {
+            ClockPolicy::Fast => return,
+            p => p.scale(),
+        };
+        let (start, start_cycles) = *self.anchor.get_or_insert((Instant::now(), cycles));
+        let emulated = timing.cycles_to_duration(cycles - start_cycles) / speed;
+        let elapsed = start.elapsed();
+        if emulated > elapsed + MIN_SLEEP {
+            thread::sleep(emulated - elapsed);
+        } else if elapsed > emulated + MAX_LAG {
+            self.anchor = Some((Instant::now(), cycles));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn spreads_the_clock_over_the_moves_to_go() {
+        // 300s over 30 moves, minus the overhead
+        assert_eq!(move_budget(secs(300), secs(0), None), secs(9));
+        assert_eq!(move_budget(secs(300), secs(0), Some(10)), secs(29));
+        // three quarters of the increment on top
+        assert_eq!(move_budget(secs(300), secs(4), None), secs(12));
+    }
+
+    #[test]
+    fn never_takes_more_than_half_of_what_is_left() {
+        assert_eq!(move_budget(secs(10), secs(0), Some(1)), secs(4));
+        assert_eq!(move_budget(secs(10), secs(60), None), secs(4));
+    }
+
+    #[test]
+    fn no_moves_to_go_plans_for_the_default() {
+        assert_eq!(
+            move_budget(secs(300), secs(0), Some(0)),
+            move_budget(secs(300), secs(0), None)
+        );
+    }
+
+    #[test]
+    fn the_overhead_never_goes_below_zero() {
+        assert_eq!(
+            move_budget(Duration::from_millis(1500), secs(0), None),
+            secs(0)
+        );
+        assert_eq!(move_budget(secs(0), secs(0), None), secs(0));
+    }
+}
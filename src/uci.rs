@@ -1,56 +1,354 @@
 use std::io;
-use std::sync::mpsc::{self, Receiver};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::thread;
+use std::time::Duration;
 use vampirc_uci::*;
 
+use crate::{
+    cli::Options,
+    emu::{new_emu, MephistoEmu, MAX_ELO, MIN_ELO},
+    log,
+    modular::{LIBRARIES, MODULES},
+    rom::RomLoader,
+    savestate::MachineState,
+    timing::ClockPolicy,
+};
+
+pub fn run(opts: &Options, roms: &RomLoader) {
+    let stdin_channel = spawn_stdin_channel();
+    let mut module = opts.module.clone();
+    let mut library = opts.library.clone();
+    let mut own_book = true;
+    let mut emu = match new_emu(&module, &library, roms) {
+        Ok(emu) => emu,
+        Err(e) => panic!("{e}"),
+    };
+    let mut inited = false;
+    let mut set_diff = opts.difficulty;
+    let mut limit_strength = false;
+    let mut elo = MAX_ELO;
+    let mut clock_mode = opts.clock_mode.clone();
+    let mut speed = opts.speed;
+    // a crystal set by the user, kept when the module changes
+    let mut crystal_hz = None;
+    emu.set_clock_policy(clock_policy(&clock_mode, speed));
+    loop {
+        match stdin_channel.try_recv() {
+            Ok(message) => match message {
+                UciMessage::Uci => print_intro(
+                    &emu.name(),
+                    &module,
+                    &library,
+                    &clock_mode,
+                    speed,
+                    emu.default_crystal_hz(),
+                    set_diff,
+                ),
+                UciMessage::IsReady => {
+                    if !inited {
+                        boot(&mut *emu, set_diff, limit_strength.then_some(elo));
+                        inited = true;
+                    }
+                    out!("{}", UciMessage::ReadyOk);
+                }
+                UciMessage::SetOption { name, value } => match name.as_str() {
+                    "Module" | "Library" | "OwnBook" => {
+                        let value = value.unwrap();
+                        let mut new = (module.clone(), library.clone(), own_book);
+                        match name.as_str() {
+                            "Module" => new.0 = value.clone(),
+                            "Library" => new.1 = value.clone(),
+                            _ => new.2 = value.to_lowercase() == "true",
+                        }
+                        if new == (module.clone(), library.clone(), own_book) {
+                            continue;
+                        }
+                        match new_emu(&new.0, library_in_use(&new.1, new.2), roms) {
+                            Ok(built) => {
+                                let (start, movs) = emu.game();
+                                emu = built;
+                                (module, library, own_book) = new;
+                                emu.set_clock_policy(clock_policy(&clock_mode, speed));
+                                if let Some(hz) = crystal_hz {
+                                    emu.set_crystal_hz(hz);
+                                }
+                                // carry on with the game at the same level, the
+                                // gui doesn't have to send isready or position again
+                                if inited {
+                                    boot(&mut *emu, set_diff, limit_strength.then_some(elo));
+                                    let startpos = start == "startpos";
+                                    emu.set_position(
+                                        startpos,
+                                        (!startpos).then_some(UciFen(start)),
+                                        movs,
+                                    );
+                                }
+                            }
+                            Err(e) => out!("info Debug could not set {name}: {e}"),
+                        }
+                    }
+                    "Difficulty" => set_diff = u8::from_str(value.unwrap().as_str()).unwrap(),
+                    "UCI_LimitStrength" | "UCI_Elo" => {
+                        let value = value.unwrap();
+                        if name == "UCI_Elo" {
+                            match u16::from_str(value.trim()) {
+                                Ok(e) if (MIN_ELO..=MAX_ELO).contains(&e) => elo = e,
+                                _ => {
+                                    out!("info Debug UCI_Elo can only be from {MIN_ELO} to {MAX_ELO}");
+                                    continue;
+                                }
+                            }
+                        } else {
+                            limit_strength = value.to_lowercase() == "true";
+                        }
+                        // before the first isready this happens when booting
+                        if inited {
+                            if limit_strength {
+                                emu.limit_strength(Some(elo)).unwrap();
+                            } else {
+                                emu.limit_strength(None).unwrap();
+                                emu.set_difficulty(Some(set_diff)).unwrap();
+                            }
+                        }
+                    }
+                    "Clock Mode" => {
+                        clock_mode = value.unwrap();
+                        emu.set_clock_policy(clock_policy(&clock_mode, speed));
+                    }
+                    "Speed" => match u32::from_str(value.unwrap().trim()) {
+                        Ok(s) if (1..=1000).contains(&s) => {
+                            speed = s;
+                            emu.set_clock_policy(clock_policy(&clock_mode, speed));
+                            if clock_mode != "Scaled" {
+                                out!("info string Speed only takes effect with Clock Mode Scaled");
+                            }
+                        }
+                        _ => out!("info Debug Speed can only be from 1 to 1000"),
+                    },
+                    "Clock kHz" => match u64::from_str(value.unwrap().trim()) {
+                        Ok(khz) if (1000..=20000).contains(&khz) => {
+                            crystal_hz = Some(khz * 1000);
+                            emu.set_crystal_hz(khz * 1000);
+                        }
+                        _ => out!("info Debug Clock kHz can only be from 1000 to 20000"),
+                    },
+                    "Save State" => {
+                        if let Err(e) = emu.save_state().save(value.unwrap().as_str()) {
+                            out!("info Debug could not save state: {e}");
+                        }
+                    }
+                    "Load State" => {
+                        match MachineState::load(value.unwrap().as_str())
+                            .and_then(|state| emu.load_game(&state))
+                        {
+                            Ok(()) => inited = true,
+                            Err(e) => out!("info Debug could not load state: {e}"),
+                        }
+                    }
+                    "Debug" | "Ponder" => {}
+                    _ => out!("info Debug unknown option: {name}, {}", value.unwrap()),
+                },
+                UciMessage::Position {
+                    startpos,
+                    fen,
+                    moves,
+                } => emu.set_position(startpos, fen, moves),
+                UciMessage::Go {
+                    time_control,
+                    search_control,
+                } => match search_control.and_then(|s| s.mate) {
+                    Some(moves) => match emu.solve_mate(&stdin_channel, moves, time_control) {
+                        Some(mov) => out!("{}", mov),
+                        None => {
+                            // the gui still wants a bestmove, the null move says there is none
+                            out!("info string no mate in {moves} found");
+                            out!("bestmove 0000");
+                        }
+                    },
+                    None => match emu.gen_move(&stdin_channel, time_control) {
+                        Some(mov) => out!("{}", mov),
+                        // no move or the machine gave up, the gui still waits for one
+                        None => out!("bestmove 0000"),
+                    },
+                },
+                UciMessage::UciNewGame => {}
+                UciMessage::Quit => return,
+                _ => out!("info Debug unhandled message: {}", message),
+            },
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => {
+                panic!("Stdin disconnected!")
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+fn boot(emu: &mut dyn MephistoEmu, difficulty: u8, elo: Option<u16>) {
+    emu.init();
+    emu.set_difficulty(Some(difficulty)).unwrap();
+    if elo.is_some() {
+        emu.limit_strength(elo).unwrap();
+    }
+}
+
+// without its own book the firmware gets no library to look at
+fn library_in_use(library: &str, own_book: bool) -> &str {
+    if own_book {
+        library
+    } else {
+        "None"
+    }
+}
+
+// vampirc drops the ponder flag of "go ponder wtime ..." in favour of the
+// clock, so the clock is kept here and handed over as a go on ponderhit.
 pub fn spawn_stdin_channel() -> Receiver<UciMessage> {
     let (tx, rx) = mpsc::channel::<UciMessage>();
     let mut debug = false;
+    let mut ponder_clock = None;
     thread::spawn(move || loop {
         let mut buffer = String::new();
-        io::stdin().read_line(&mut buffer).unwrap();
-        let message = parse_one(&buffer);
+        if io::stdin().read_line(&mut buffer).unwrap() == 0 {
+            // the gui went away
+            let _ = tx.send(UciMessage::Quit);
+            break;
+        }
+        log::incoming(&buffer);
+        let mut message = parse_one(&buffer);
+        match message {
+            UciMessage::Go {
+                time_control,
+                search_control,
+            } if buffer
+                .split_whitespace()
+                .any(|w| w.eq_ignore_ascii_case("ponder")) =>
+            {
+                ponder_clock = time_control.filter(|tc| *tc != UciTimeControl::Ponder);
+                message = UciMessage::Go {
+                    time_control: Some(UciTimeControl::Ponder),
+                    search_control,
+                };
+            }
+            UciMessage::PonderHit => {
+                message = UciMessage::Go {
+                    time_control: ponder_clock.take(),
+                    search_control: None,
+                }
+            }
+            _ => {}
+        }
         if debug {
-            println!("info Debug recieved command: {message}")
+            out!("info Debug recieved command: {message}")
         }
         if message.is_unknown() {
             continue;
         }
-        match message.clone() {
-            UciMessage::SetOption { name, value } => {
-                if name == "Debug" && value.is_some() {
-                    debug = value.unwrap().to_lowercase() == "true";
-                }
+        if let UciMessage::SetOption {
+            name,
+            value: Some(value),
+        } = message.clone()
+        {
+            if name == "Debug" {
+                debug = value.to_lowercase() == "true";
             }
-            _ => {}
         }
-        if let Err(_) = tx.send(message) {
+        if tx.send(message).is_err() {
             break;
         }
     });
     rx
 }
-pub fn print_intro() {
+pub fn clock_policy(mode: &str, speed: u32) -> ClockPolicy {
+    match mode {
+        "RealTime" => ClockPolicy::RealTime,
+        "Scaled" => ClockPolicy::Scaled(speed),
+        _ => ClockPolicy::Fast,
+    }
+}
+pub fn print_intro(
+    name: &str,
+    module: &str,
+    library: &str,
+    clock_mode: &str,
+    speed: u32,
+    crystal_hz: u64,
+    difficulty: u8,
+) {
     let options = vec![
+        UciOptionConfig::Combo {
+            name: "Module".to_string(),
+            default: Some(module.to_string()),
+            var: MODULES.iter().map(|m| m.to_string()).collect(),
+        },
+        UciOptionConfig::Combo {
+            name: "Library".to_string(),
+            default: Some(library.to_string()),
+            var: LIBRARIES.iter().map(|l| l.to_string()).collect(),
+        },
         UciOptionConfig::Spin {
             name: "Difficulty".to_string(),
-            default: Some(1),
+            default: Some(difficulty as i64),
             min: Some(1),
             max: Some(10),
         },
+        UciOptionConfig::Check {
+            name: "UCI_LimitStrength".to_string(),
+            default: Some(false),
+        },
+        UciOptionConfig::Spin {
+            name: "UCI_Elo".to_string(),
+            default: Some(MAX_ELO as i64),
+            min: Some(MIN_ELO as i64),
+            max: Some(MAX_ELO as i64),
+        },
+        UciOptionConfig::Combo {
+            name: "Clock Mode".to_string(),
+            default: Some(clock_mode.to_string()),
+            var: vec![
+                "Fast".to_string(),
+                "RealTime".to_string(),
+                "Scaled".to_string(),
+            ],
+        },
+        UciOptionConfig::Spin {
+            name: "Speed".to_string(),
+            default: Some(speed as i64),
+            min: Some(1),
+            max: Some(1000),
+        },
+        UciOptionConfig::Spin {
+            name: "Clock kHz".to_string(),
+            default: Some((crystal_hz / 1000) as i64),
+            min: Some(1000),
+            max: Some(20000),
+        },
         UciOptionConfig::Check {
             name: "OwnBook".to_string(),
             default: Some(true),
         },
+        UciOptionConfig::Check {
+            name: "Ponder".to_string(),
+            default: Some(false),
+        },
+        UciOptionConfig::String {
+            name: "Save State".to_string(),
+            default: None,
+        },
+        UciOptionConfig::String {
+            name: "Load State".to_string(),
+            default: None,
+        },
         UciOptionConfig::Check {
             name: "Debug".to_string(),
             default: Some(false),
         },
     ];
-    println!(
+    out!(
         "{}\n{}\n",
         UciMessage::Id {
-            name: Some("Mephisto MM2".to_string()),
+            name: Some(name.to_string()),
             author: None
         },
         UciMessage::Id {
@@ -59,7 +357,7 @@ pub fn print_intro() {
         }
     );
     for o in options {
-        println!("{}", UciMessage::Option(o));
+        out!("{}", UciMessage::Option(o));
     }
-    println!("{}", UciMessage::UciOk)
+    out!("{}", UciMessage::UciOk)
 }
@@ -0,0 +1,387 @@
+use std::{
+    io, process,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+use vampirc_uci::{parse_one, UciFen, UciInfoAttribute, UciMessage, UciTimeControl};
+
+use crate::{
+    cli::Options,
+    emu::{new_emu, MephistoEmu},
+    log,
+    rom::RomLoader,
+    timing::move_budget,
+    uci::clock_policy,
+};
+
+// CECP wants mates as scores beyond this
+const MATE_SCORE: i32 = 100000;
+
+// The last score and depth the machine showed, picked out of its info lines,
+// and whether they go to the gui while it thinks.
+#[derive(Clone, Copy, Default)]
+struct Thinking {
+    score: i32,
+    depth: u8,
+    post: bool,
+}
+
+// How much time the machine gets for a move.
+enum Clock {
+    // st: exactly this many milliseconds
+    PerMove(u64),
+    // level: moves per session (0 for all), base and increment in ms
+    Level { mps: u64, base: u64, inc: u64 },
+}
+
+// level takes the base as minutes or minutes:seconds
+fn parse_minutes(s: &str) -> Option<u64> {
+    let (min, sec) = s.split_once(':').unwrap_or((s, "0"));
+    Some((min.parse::<f64>().ok()? * 60_000.0) as u64 + sec.parse::<u64>().ok()? * 1000)
+}
+
+// An info line of the machine as thinking output if it has a pv and post is
+// on, as a comment otherwise. Score and depth are kept for later lines either way.
+fn shown_line(t: &mut Thinking, line: &str) -> String {
+    let UciMessage::Info(attrs) = parse_one(line) else {
+        return format!("# {line}");
+    };
+    let mut centis = None;
+    let mut pv = None;
+    for attr in attrs {
+        match attr {
+            UciInfoAttribute::Score { cp: Some(cp), .. } => t.score = cp,
+            UciInfoAttribute::Score { mate: Some(n), .. } => {
+                t.score = (MATE_SCORE + n.unsigned_abs() as i32) * n.signum() as i32
+            }
+            UciInfoAttribute::Depth(d) => t.depth = d,
+            UciInfoAttribute::Time(time) => centis = Some(time.num_milliseconds() / 10),
+            UciInfoAttribute::Pv(moves) => {
+                pv = Some(moves.iter().map(|m| m.to_string()).collect::<Vec<_>>())
+            }
+            _ => {}
+        }
+    }
+    match (t.post, centis, pv) {
+        (true, Some(centis), Some(pv)) => {
+            format!("{} {} {centis} 0 {}", t.depth, t.score, pv.join(" "))
+        }
+        _ => format!("# {line}"),
+    }
+}
+
+struct Xboard {
+    emu: Box<dyn MephistoEmu>,
+    stop: Receiver<UciMessage>,
+    thinking: Arc<Mutex<Thinking>>,
+    start: Board,
+    moves: Vec<ChessMove>,
+    engine_color: Option<Color>,
+    clock: Clock,
+    // our remaining time in ms, from the time command
+    time_left: Option<u64>,
+    // moves we made since the time control was set, for the moves to go
+    moves_made: u64,
+    // emulated time per unit of wall time, from the clock policy
+    scale: u32,
+    inited: bool,
+}
+
+impl Xboard {
+    fn board(&self) -> Board {
+        self.moves
+            .iter()
+            .fold(self.start, |b, m| b.make_move_new(*m))
+    }
+    fn movetime(&self) -> u64 {
+        match self.clock {
+            Clock::PerMove(ms) => ms,
+            Clock::Level { mps, base, inc } => {
+                let left = self.time_left.unwrap_or(base);
+                let to_go = (mps > 0).then(|| (mps - self.moves_made % mps) as u32);
+                move_budget(
+                    Duration::from_millis(left),
+                    Duration::from_millis(inc),
+                    to_go,
+                )
+                .as_millis() as u64
+            }
+        }
+    }
+    fn init(&mut self, difficulty: u8) {
+        if !self.inited {
+            self.emu.init();
+            self.emu.set_difficulty(Some(difficulty)).unwrap();
+            self.inited = true;
+        }
+    }
+    fn new_game(&mut self, start: Board) {
+        self.start = start;
+        self.moves.clear();
+    }
+    fn think(&mut self) {
+        let board = self.board();
+        if board.status() != BoardStatus::Ongoing {
+            return;
+        }
+        let startpos = self.start == Board::default();
+        let fen = (!startpos).then(|| UciFen(self.start.to_string()));
+        self.emu.set_position(startpos, fen, self.moves.clone());
+        // a move now that came while we weren't thinking is stale
+        for message in self.stop.try_iter() {
+            if matches!(message, UciMessage::Quit) {
+                process::exit(0);
+            }
+        }
+        {
+            let mut thinking = self.thinking.lock().unwrap();
+            *thinking = Thinking {
+                post: thinking.post,
+                ..Thinking::default()
+            };
+        }
+        let started = self.emu.virtual_now();
+        let time =
+            UciTimeControl::MoveTime(vampirc_uci::Duration::milliseconds(self.movetime() as i64));
+        let Some(UciMessage::BestMove { best_move, ponder }) =
+            self.emu.gen_move(&self.stop, Some(time))
+        else {
+            log::reply("resign");
+            return;
+        };
+        if self.thinking.lock().unwrap().post {
+            let thinking = *self.thinking.lock().unwrap();
+            let centis = ((self.emu.virtual_now() - started) / self.scale).as_millis() / 10;
+            let pv = match ponder {
+                Some(p) => format!("{best_move} {p}"),
+                None => best_move.to_string(),
+            };
+            log::reply(&format!(
+                "{} {} {centis} 0 {pv}",
+                thinking.depth, thinking.score
+            ));
+        }
+        self.moves.push(best_move);
+        self.moves_made += 1;
+        log::reply(&format!("move {best_move}"));
+        self.report_result();
+    }
+    fn report_result(&self) {
+        let board = self.board();
+        match board.status() {
+            BoardStatus::Checkmate => log::reply(match board.side_to_move() {
+                Color::White => "0-1 {Black mates}",
+                Color::Black => "1-0 {White mates}",
+            }),
+            BoardStatus::Stalemate => log::reply("1/2-1/2 {Stalemate}"),
+            BoardStatus::Ongoing => {}
+        }
+    }
+    fn user_move(&mut self, text: &str) {
+        let board = self.board();
+        let mov = ChessMove::from_str(text)
+            .ok()
+            .filter(|m| board.legal(*m))
+            .or_else(|| ChessMove::from_san(&board, text).ok());
+        match mov {
+            Some(m) => {
+                self.moves.push(m);
+                self.report_result();
+                if self.engine_color == Some(self.board().side_to_move()) {
+                    self.think();
+                }
+            }
+            None => log::reply(&format!("Illegal move: {text}")),
+        }
+    }
+}
+
+// Reads CECP commands. Move now and quit have to reach the machine while it
+// thinks, everything else waits for the front end.
+fn spawn_stdin_channel() -> (Receiver<String>, Receiver<UciMessage>) {
+    let (cmd_tx, cmd_rx) = mpsc::channel::<String>();
+    let (stop_tx, stop_rx): (Sender<UciMessage>, _) = mpsc::channel();
+    thread::spawn(move || loop {
+        let mut buffer = String::new();
+        if io::stdin().read_line(&mut buffer).unwrap() == 0 {
+            let _ = stop_tx.send(UciMessage::Quit);
+            let _ = cmd_tx.send("quit".to_string());
+            break;
+        }
+        log::incoming(&buffer);
+        let line = buffer.trim().to_string();
+        match line.as_str() {
+            "?" => {
+                let _ = stop_tx.send(UciMessage::Stop);
+                continue;
+            }
+            "quit" => {
+                let _ = stop_tx.send(UciMessage::Quit);
+            }
+            _ => {}
+        }
+        if cmd_tx.send(line).is_err() {
+            break;
+        }
+    });
+    (cmd_rx, stop_rx)
+}
+
+pub fn run(opts: &Options, roms: &RomLoader) {
+    let (commands, stop) = spawn_stdin_channel();
+    // the machine's info lines become thinking output, the rest comments
+    let thinking = Arc::new(Mutex::new(Thinking::default()));
+    let seen = thinking.clone();
+    log::set_filter(move |line| Some(shown_line(&mut seen.lock().unwrap(), line)));
+    let emu = match new_emu(&opts.module, &opts.library, roms) {
+        Ok(emu) => emu,
+        Err(e) => {
+            log::reply(&format!("tellusererror {e}"));
+            process::exit(1);
+        }
+    };
+    let policy = clock_policy(&opts.clock_mode, opts.speed);
+    let mut xb = Xboard {
+        emu,
+        stop,
+        thinking,
+        start: Board::default(),
+        moves: Vec::new(),
+        engine_color: Some(Color::Black),
+        clock: Clock::PerMove(opts.movetime),
+        time_left: None,
+        moves_made: 0,
+        scale: policy.scale(),
+        inited: false,
+    };
+    xb.emu.set_clock_policy(policy);
+    for line in commands.iter() {
+        let (cmd, args) = line.split_once(' ').unwrap_or((&line, ""));
+        if !matches!(cmd, "xboard" | "protover" | "accepted" | "rejected") {
+            xb.init(opts.difficulty);
+        }
+        match cmd {
+            "xboard" | "accepted" | "rejected" | "random" | "computer" | "hard" | "easy"
+            | "result" | "name" | "rating" | "otim" | "sd" => {}
+            "protover" => {
+                // booting takes a while, tell the gui to wait for it
+                log::reply("feature done=0");
+                xb.init(opts.difficulty);
+                log::reply(&format!(
+                    "feature myname=\"{}\" setboard=1 usermove=1 ping=1 sigint=0 sigterm=0 analyze=0 colors=0 done=1",
+                    xb.emu.name()
+                ));
+            }
+            "new" => {
+                xb.new_game(Board::default());
+                xb.engine_color = Some(Color::Black);
+                xb.time_left = None;
+                xb.moves_made = 0;
+            }
+            "force" => xb.engine_color = None,
+            "go" => {
+                xb.engine_color = Some(xb.board().side_to_move());
+                xb.think();
+            }
+            "white" | "black" => {
+                // protocol 1: the engine plays the other colour
+                xb.engine_color = Some(if cmd == "white" {
+                    Color::Black
+                } else {
+                    Color::White
+                });
+            }
+            "playother" => xb.engine_color = Some(!xb.board().side_to_move()),
+            "usermove" => xb.user_move(args),
+            "st" => match args.parse::<f64>() {
+                Ok(s) => xb.clock = Clock::PerMove((s * 1000.0) as u64),
+                Err(_) => log::reply(&format!("Error (bad time): {line}")),
+            },
+            "level" => {
+                let f: Vec<&str> = args.split_whitespace().collect();
+                match (
+                    f.first().and_then(|m| m.parse().ok()),
+                    f.get(1).and_then(|b| parse_minutes(b)),
+                    f.get(2).and_then(|i| i.parse::<f64>().ok()),
+                ) {
+                    (Some(mps), Some(base), Some(inc)) => {
+                        xb.clock = Clock::Level {
+                            mps,
+                            base,
+                            inc: (inc * 1000.0) as u64,
+                        };
+                        xb.moves_made = 0;
+                    }
+                    _ => log::reply(&format!("Error (bad level): {line}")),
+                }
+            }
+            // past the flag the clock goes negative, which leaves nothing to spend
+            "time" => xb.time_left = args.parse::<i64>().ok().map(|cs| cs.max(0) as u64 * 10),
+            "undo" => {
+                xb.moves.pop();
+            }
+            "remove" => {
+                xb.moves.pop();
+                xb.moves.pop();
+                xb.moves_made = xb.moves_made.saturating_sub(1);
+            }
+            "setboard" => match Board::from_str(args) {
+                Ok(board) => xb.new_game(board),
+                Err(_) => log::reply(&format!("tellusererror Illegal position: {args}")),
+            },
+            "post" | "nopost" => xb.thinking.lock().unwrap().post = cmd == "post",
+            "ping" => log::reply(&format!("pong {args}")),
+            "quit" => return,
+            _ => {
+                // without usermove=1 moves come on their own
+                let board = xb.board();
+                if ChessMove::from_str(&line).is_ok_and(|m| board.legal(m)) {
+                    xb.user_move(&line);
+                } else {
+                    log::reply(&format!("Error (unknown command): {line}"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_level_minutes() {
+        assert_eq!(parse_minutes("5"), Some(300_000));
+        assert_eq!(parse_minutes("0:30"), Some(30_000));
+        assert_eq!(parse_minutes("2:05"), Some(125_000));
+        assert_eq!(parse_minutes("0.5"), Some(30_000));
+        assert_eq!(parse_minutes("five"), None);
+        assert_eq!(parse_minutes("1:xx"), None);
+    }
+
+    #[test]
+    fn posts_info_lines_with_a_pv() {
+        let mut t = Thinking::default();
+        let line = "info depth 4 score cp -35 time 1230 pv e2e4 e7e5";
+        assert_eq!(shown_line(&mut t, line), format!("# {line}"));
+        t.post = true;
+        assert_eq!(shown_line(&mut t, line), "4 -35 123 0 e2e4 e7e5");
+        // the depth and score stay for lines without them
+        assert_eq!(
+            shown_line(&mut t, "info time 2000 pv d2d4"),
+            "4 -35 200 0 d2d4"
+        );
+        assert_eq!(shown_line(&mut t, "info time 2500"), "# info time 2500");
+        assert_eq!(
+            shown_line(&mut t, "info string no mate in 3 found"),
+            "# info string no mate in 3 found"
+        );
+    }
+}